@@ -0,0 +1,515 @@
+//! `#[derive(Trace)]` for `UnsafeCells::cc::Trace`.
+//!
+//! The derived trace() traces every field of a struct, or of the current
+//! variant of an enum. Fields that can't hold a CcRc, or that shouldn't be
+//! followed by the cycle collector, can be left out with `#[trace(skip)]`.
+//! A type parameter gets a `Trace` bound if its name shows up anywhere in the
+//! type of a traced field, so a parameter only used by skipped fields can be
+//! anything. That's decided by name only: `PhantomData<T>` in a traced field
+//! still bounds `T`.
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use UnsafeCells::cc::{collect_cycles, CcRc};
+//! use UnsafeCells::refcell::RefCell;
+//! use UnsafeCells_derive::Trace;
+//!
+//! #[derive(Trace)]
+//! struct Scope {
+//!     vars: RefCell<HashMap<String, CcRc<Scope>>>,
+//!     #[trace(skip)]
+//!     created: std::time::Instant,
+//! }
+//!
+//! let scope = CcRc::new(Scope {
+//!     vars: RefCell::new(HashMap::new()),
+//!     created: std::time::Instant::now(),
+//! });
+//! scope.vars.borrow_mut().insert("self".to_string(), scope.clone());
+//! drop(scope);
+//! assert_eq!(collect_cycles(), 1);
+//! ```
+//!
+//! Every field that isn't skipped must implement Trace:
+//!
+//! ```compile_fail,E0277
+//! use UnsafeCells_derive::Trace;
+//!
+//! #[derive(Trace)]
+//! struct Timer {
+//!     elapsed: std::time::Duration,
+//! }
+//! ```
+//!
+//! Unions can't be traced, since there's no telling which field is in use:
+//!
+//! ```compile_fail
+//! use UnsafeCells_derive::Trace;
+//!
+//! #[derive(Trace)]
+//! union Bits {
+//!     int: u32,
+//!     float: f32,
+//! }
+//! ```
+//!
+//! and `skip` is the only option `#[trace]` takes:
+//!
+//! ```compile_fail
+//! use UnsafeCells_derive::Trace;
+//!
+//! #[derive(Trace)]
+//! struct Counter {
+//!     #[trace(ignore)]
+//!     count: u32,
+//! }
+//! ```
+//!
+//! It's only allowed on fields, not on the type:
+//!
+//! ```compile_fail
+//! use UnsafeCells_derive::Trace;
+//!
+//! #[derive(Trace)]
+//! #[trace(skip)]
+//! struct Counter {
+//!     count: u32,
+//! }
+//! ```
+//!
+//! or on an enum variant:
+//!
+//! ```compile_fail
+//! use UnsafeCells_derive::Trace;
+//!
+//! #[derive(Trace)]
+//! enum Event {
+//!     #[trace(skip)]
+//!     Tick(std::time::Instant),
+//!     Count(u32),
+//! }
+//! ```
+
+// The output is put together as a string and parsed back into a TokenStream,
+// and the input is picked apart by hand. Only as much of it is understood as
+// the impl needs: the name, the generics, the where clause and which fields
+// there are.
+
+#![allow(non_snake_case)]
+
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+use std::iter::FromIterator;
+
+const TRACE: &str = "::UnsafeCells::cc::Trace";
+
+#[proc_macro_derive(Trace, attributes(trace))]
+pub fn derive_trace(input: TokenStream) -> TokenStream {
+    let output = match expand(input) {
+        Ok(output) => output,
+        Err(msg) => format!("compile_error!({:?});", msg),
+    };
+    output.parse().unwrap()
+}
+
+// A named field ("name") or tuple field ("0") that isn't skipped
+type Fields = Vec<String>;
+
+enum Body {
+    // braces or parens, with the number of fields for the latter
+    Named(Fields),
+    Tuple(Fields, usize),
+    Unit,
+}
+
+struct Generics {
+    // for `impl<...>`: the params without their defaults
+    impl_params: Vec<String>,
+    // for `Name<...>`: just the names
+    type_args: Vec<String>,
+    // the type params, which need a Trace bound if a traced field uses them
+    type_params: Vec<String>,
+}
+
+fn expand(input: TokenStream) -> Result<String, String> {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    let mut i = 0;
+    if skip_attrs(&tokens, &mut i)? {
+        return Err("#[trace(skip)] goes on fields, not on the type".to_string());
+    }
+    skip_vis(&tokens, &mut i);
+
+    let kind = ident_at(&tokens, i).ok_or("expected a struct or enum")?;
+    let name = ident_at(&tokens, i + 1).ok_or("expected a type name")?;
+    i += 2;
+    let generics = parse_generics(&tokens, &mut i);
+    // every identifier in the types of the traced fields
+    let mut traced_idents = Vec::new();
+
+    match kind.as_str() {
+        "struct" => {
+            // tuple structs have the where clause after the fields
+            let mut body = Body::Unit;
+            if let Some(TokenTree::Group(group)) = tokens.get(i) {
+                if group.delimiter() == Delimiter::Parenthesis {
+                    body = parse_fields(group.stream(), false, &mut traced_idents)?;
+                    i += 1;
+                }
+            }
+            let where_clause = parse_where(&tokens, &mut i);
+            if let Some(TokenTree::Group(group)) = tokens.get(i) {
+                if group.delimiter() == Delimiter::Brace {
+                    body = parse_fields(group.stream(), true, &mut traced_idents)?;
+                }
+            }
+            let method = trace_struct(&body);
+            Ok(trace_impl(
+                &name,
+                &generics,
+                &where_clause,
+                &traced_idents,
+                &method,
+            ))
+        }
+        "enum" => {
+            let where_clause = parse_where(&tokens, &mut i);
+            let variants = match tokens.get(i) {
+                Some(TokenTree::Group(group)) => {
+                    parse_variants(group.stream(), &mut traced_idents)?
+                }
+                _ => return Err("expected enum variants".to_string()),
+            };
+            let method = trace_enum(&variants);
+            Ok(trace_impl(
+                &name,
+                &generics,
+                &where_clause,
+                &traced_idents,
+                &method,
+            ))
+        }
+        "union" => Err("#[derive(Trace)] can't be used on unions".to_string()),
+        _ => Err("expected a struct or enum".to_string()),
+    }
+}
+
+fn trace_impl(
+    name: &str,
+    generics: &Generics,
+    where_clause: &[String],
+    traced_idents: &[String],
+    body: &str,
+) -> String {
+    let mut predicates = where_clause.to_vec();
+    for param in &generics.type_params {
+        if traced_idents.contains(param) {
+            predicates.push(format!("{}: {}", param, TRACE));
+        }
+    }
+    format!(
+        "unsafe impl{} {} for {}{} {} {{
+            #[allow(unused_variables)]
+            fn trace(&self, tracer: &mut ::UnsafeCells::cc::Tracer<'_>) {{ {} }}
+        }}",
+        angle_list(&generics.impl_params),
+        TRACE,
+        name,
+        angle_list(&generics.type_args),
+        if predicates.is_empty() {
+            String::new()
+        } else {
+            format!("where {}", predicates.join(", "))
+        },
+        body,
+    )
+}
+
+fn angle_list(items: &[String]) -> String {
+    if items.is_empty() {
+        String::new()
+    } else {
+        format!("<{}>", items.join(", "))
+    }
+}
+
+fn trace_call(expr: &str) -> String {
+    format!("{}::trace({}, tracer);", TRACE, expr)
+}
+
+fn trace_struct(body: &Body) -> String {
+    let fields = match body {
+        Body::Named(fields) | Body::Tuple(fields, _) => fields,
+        Body::Unit => return String::new(),
+    };
+    fields
+        .iter()
+        .map(|field| trace_call(&format!("&self.{}", field)))
+        .collect()
+}
+
+fn trace_enum(variants: &[(String, Body)]) -> String {
+    if variants.is_empty() {
+        return "match *self {}".to_string();
+    }
+    let mut arms = String::new();
+    for (name, body) in variants {
+        // bind the fields to __0, __1... so they can't shadow `tracer`
+        let (pattern, calls) = match body {
+            Body::Named(fields) => {
+                let bindings: Vec<String> = fields
+                    .iter()
+                    .enumerate()
+                    .map(|(n, field)| format!("{}: __{}", field, n))
+                    .collect();
+                let calls = (0..fields.len())
+                    .map(|n| trace_call(&format!("__{}", n)))
+                    .collect();
+                (format!("{{ {} .. }}", with_commas(&bindings)), calls)
+            }
+            Body::Tuple(fields, len) => {
+                let bindings: Vec<String> = (0..*len)
+                    .map(|n| {
+                        if fields.contains(&n.to_string()) {
+                            format!("__{}", n)
+                        } else {
+                            "_".to_string()
+                        }
+                    })
+                    .collect();
+                let calls = fields
+                    .iter()
+                    .map(|n| trace_call(&format!("__{}", n)))
+                    .collect();
+                (format!("({})", bindings.join(", ")), calls)
+            }
+            Body::Unit => (String::new(), String::new()),
+        };
+        arms.push_str(&format!("Self::{} {} => {{ {} }}\n", name, pattern, calls));
+    }
+    format!("match self {{ {} }}", arms)
+}
+
+// "a, b, " so that `..` can follow
+fn with_commas(items: &[String]) -> String {
+    items.iter().map(|item| format!("{}, ", item)).collect()
+}
+
+fn ident_at(tokens: &[TokenTree], i: usize) -> Option<String> {
+    match tokens.get(i) {
+        Some(TokenTree::Ident(ident)) => Some(ident.to_string()),
+        _ => None,
+    }
+}
+
+fn is_punct(token: Option<&TokenTree>, c: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(p)) if p.as_char() == c)
+}
+
+fn to_string(tokens: &[TokenTree]) -> String {
+    TokenStream::from_iter(tokens.iter().cloned()).to_string()
+}
+
+// Adds every identifier in `tokens` to `idents`, including the ones nested in
+// groups, e.g. the T in `[T; 4]`
+fn collect_idents(tokens: &[TokenTree], idents: &mut Vec<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => idents.push(ident.to_string()),
+            TokenTree::Group(group) => {
+                let inner: Vec<TokenTree> = group.stream().into_iter().collect();
+                collect_idents(&inner, idents);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Skips `#[...]` attributes, returning whether one of them was #[trace(skip)]
+fn skip_attrs(tokens: &[TokenTree], i: &mut usize) -> Result<bool, String> {
+    let mut skip = false;
+    while is_punct(tokens.get(*i), '#') {
+        if let Some(TokenTree::Group(group)) = tokens.get(*i + 1) {
+            let attr: Vec<TokenTree> = group.stream().into_iter().collect();
+            if ident_at(&attr, 0).as_deref() == Some("trace") {
+                let option = match attr.get(1) {
+                    Some(TokenTree::Group(args)) if attr.len() == 2 => args.stream().to_string(),
+                    _ => String::new(),
+                };
+                if option != "skip" {
+                    return Err("expected #[trace(skip)]".to_string());
+                }
+                skip = true;
+            }
+        }
+        *i += 2;
+    }
+    Ok(skip)
+}
+
+// Skips `pub`, `pub(crate)` and the like
+fn skip_vis(tokens: &[TokenTree], i: &mut usize) {
+    if ident_at(tokens, *i).as_deref() == Some("pub") {
+        *i += 1;
+        if let Some(TokenTree::Group(group)) = tokens.get(*i) {
+            if group.delimiter() == Delimiter::Parenthesis {
+                *i += 1;
+            }
+        }
+    }
+}
+
+// Walks over `tokens` keeping track of how deep inside <...> we are, which
+// matters because the commas in `HashMap<K, V>` aren't inside a Group. The >
+// of an `->` doesn't count.
+fn angle_depths(tokens: &[TokenTree]) -> Vec<i32> {
+    let mut depth = 0;
+    let mut after_dash = false;
+    let mut depths = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        if let TokenTree::Punct(p) = token {
+            match p.as_char() {
+                '<' => depth += 1,
+                '>' if !after_dash => depth -= 1,
+                _ => {}
+            }
+        }
+        depths.push(depth);
+        after_dash = matches!(token, TokenTree::Punct(p)
+            if p.as_char() == '-' && p.spacing() == Spacing::Joint);
+    }
+    depths
+}
+
+// Splits at the commas that aren't nested inside <...>, dropping empty parts
+// (from a trailing comma)
+fn split_commas(tokens: &[TokenTree]) -> Vec<Vec<TokenTree>> {
+    let depths = angle_depths(tokens);
+    let mut parts = vec![Vec::new()];
+    for (token, depth) in tokens.iter().zip(depths) {
+        if depth == 0 && is_punct(Some(token), ',') {
+            parts.push(Vec::new());
+        } else {
+            parts.last_mut().unwrap().push(token.clone());
+        }
+    }
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+// Parses the `<...>` after the type name, if any
+fn parse_generics(tokens: &[TokenTree], i: &mut usize) -> Generics {
+    let mut generics = Generics {
+        impl_params: Vec::new(),
+        type_args: Vec::new(),
+        type_params: Vec::new(),
+    };
+    if !is_punct(tokens.get(*i), '<') {
+        return generics;
+    }
+    let depths = angle_depths(&tokens[*i..]);
+    let len = depths
+        .iter()
+        .position(|&depth| depth == 0)
+        .unwrap_or(depths.len());
+    let params = &tokens[*i + 1..*i + len];
+    *i += len + 1;
+
+    for param in split_commas(params) {
+        // leave out the default, `= ...`
+        let depths = angle_depths(&param);
+        let end = param
+            .iter()
+            .zip(depths)
+            .position(|(token, depth)| depth == 0 && is_punct(Some(token), '='))
+            .unwrap_or(param.len());
+        generics.impl_params.push(to_string(&param[..end]));
+        if is_punct(param.first(), '\'') {
+            generics.type_args.push(to_string(&param[..2]));
+        } else if ident_at(&param, 0).as_deref() == Some("const") {
+            generics
+                .type_args
+                .push(ident_at(&param, 1).unwrap_or_default());
+        } else if let Some(name) = ident_at(&param, 0) {
+            generics.type_args.push(name.clone());
+            generics.type_params.push(name);
+        }
+    }
+    generics
+}
+
+// Parses `where ...` up to the body, if there is one
+fn parse_where(tokens: &[TokenTree], i: &mut usize) -> Vec<String> {
+    if ident_at(tokens, *i).as_deref() != Some("where") {
+        return Vec::new();
+    }
+    *i += 1;
+    let start = *i;
+    while let Some(token) = tokens.get(*i) {
+        match token {
+            TokenTree::Group(group) if group.delimiter() == Delimiter::Brace => break,
+            TokenTree::Punct(p) if p.as_char() == ';' => break,
+            _ => *i += 1,
+        }
+    }
+    split_commas(&tokens[start..*i])
+        .iter()
+        .map(|predicate| to_string(predicate))
+        .collect()
+}
+
+// Also adds the identifiers in the types of the traced fields to
+// `traced_idents`
+fn parse_fields(
+    stream: TokenStream,
+    named: bool,
+    traced_idents: &mut Vec<String>,
+) -> Result<Body, String> {
+    let tokens: Vec<TokenTree> = stream.into_iter().collect();
+    let fields = split_commas(&tokens);
+    let mut traced = Vec::new();
+    for (n, field) in fields.iter().enumerate() {
+        let mut i = 0;
+        let skip = skip_attrs(field, &mut i)?;
+        skip_vis(field, &mut i);
+        if skip {
+            continue;
+        }
+        if named {
+            traced.push(ident_at(field, i).ok_or("expected a field name")?);
+            // skip `name:`
+            i += 2;
+        } else {
+            traced.push(n.to_string());
+        }
+        collect_idents(&field[i.min(field.len())..], traced_idents);
+    }
+    Ok(if named {
+        Body::Named(traced)
+    } else {
+        Body::Tuple(traced, fields.len())
+    })
+}
+
+fn parse_variants(
+    stream: TokenStream,
+    traced_idents: &mut Vec<String>,
+) -> Result<Vec<(String, Body)>, String> {
+    let tokens: Vec<TokenTree> = stream.into_iter().collect();
+    let mut variants = Vec::new();
+    for variant in split_commas(&tokens) {
+        let mut i = 0;
+        if skip_attrs(&variant, &mut i)? {
+            return Err("#[trace(skip)] goes on fields, not on enum variants".to_string());
+        }
+        let name = ident_at(&variant, i).ok_or("expected a variant name")?;
+        let body = match variant.get(i + 1) {
+            Some(TokenTree::Group(group)) => match group.delimiter() {
+                Delimiter::Brace => parse_fields(group.stream(), true, traced_idents)?,
+                _ => parse_fields(group.stream(), false, traced_idents)?,
+            },
+            // a unit variant, maybe with `= discriminant`
+            _ => Body::Unit,
+        };
+        variants.push((name, body));
+    }
+    Ok(variants)
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use UnsafeCells::cc::{collect_cycles, CcRc};
+use UnsafeCells::cell::Cell;
+use UnsafeCells::rc::Rc;
+use UnsafeCells::refcell::RefCell;
+use UnsafeCells_derive::Trace;
+
+#[derive(Trace)]
+struct Node {
+    edges: RefCell<Vec<CcRc<Node>>>,
+    by_name: RefCell<HashMap<String, CcRc<Node>>>,
+    next: RefCell<Option<CcRc<Node>>>,
+    visits: Cell<u32>,
+    #[trace(skip)]
+    hidden: RefCell<Option<CcRc<Node>>>,
+}
+
+fn node() -> CcRc<Node> {
+    CcRc::new(Node {
+        edges: RefCell::new(Vec::new()),
+        by_name: RefCell::new(HashMap::new()),
+        next: RefCell::new(None),
+        visits: Cell::new(0),
+        hidden: RefCell::new(None),
+    })
+}
+
+#[test]
+fn named_fields_are_traced() {
+    let a = node();
+    let b = node();
+    let c = node();
+    a.edges.borrow_mut().push(b.clone());
+    b.by_name.borrow_mut().insert("c".to_string(), c.clone());
+    *c.next.borrow_mut() = Some(a.clone());
+    drop((a, b, c));
+    assert_eq!(collect_cycles(), 3);
+}
+
+#[test]
+fn skipped_fields_are_not_traced() {
+    let a = node();
+    *a.hidden.borrow_mut() = Some(a.clone());
+    let kept = a.clone();
+    drop(a);
+    // the cycle goes through a skipped field, so it looks referenced from
+    // outside and is kept
+    assert_eq!(collect_cycles(), 0);
+    assert_eq!(CcRc::strong_count(&kept), 2);
+    kept.hidden.borrow_mut().take();
+}
+
+#[derive(Trace)]
+struct Pair<T, U: Clone = u8>(T, #[trace(skip)] U)
+where
+    T: Default;
+
+#[derive(Trace)]
+enum Value {
+    Nil,
+    Int(i64),
+    List(RefCell<Vec<CcRc<Value>>>),
+    Table {
+        fields: RefCell<HashMap<String, CcRc<Value>>>,
+        #[trace(skip)]
+        meta: RefCell<Option<CcRc<Value>>>,
+        tracer: u8,
+    },
+    Boxed(Box<Option<CcRc<Value>>>, #[trace(skip)] u16),
+}
+
+#[test]
+fn enum_variants_are_traced() {
+    let list = CcRc::new(Value::List(RefCell::new(Vec::new())));
+    let table = CcRc::new(Value::Table {
+        fields: RefCell::new(HashMap::new()),
+        meta: RefCell::new(None),
+        tracer: 0,
+    });
+    if let Value::List(items) = &*list {
+        items.borrow_mut().push(table.clone());
+        items.borrow_mut().push(CcRc::new(Value::Int(1)));
+        items.borrow_mut().push(CcRc::new(Value::Nil));
+    }
+    if let Value::Table { fields, meta, .. } = &*table {
+        fields.borrow_mut().insert("list".to_string(), list.clone());
+        assert!(meta.borrow().is_none());
+    }
+    let boxed = CcRc::new(Value::Boxed(Box::new(Some(list.clone())), 7));
+    if let Value::Boxed(_, tag) = &*boxed {
+        assert_eq!(*tag, 7);
+    }
+    drop((list, table));
+    // boxed keeps the cycle alive
+    assert_eq!(collect_cycles(), 0);
+    drop(boxed);
+    // the Int and Nil only hang off the cycle, so they go with it
+    assert_eq!(collect_cycles(), 4);
+}
+
+#[test]
+fn generic_types_are_traced() {
+    let a = CcRc::new(Pair(RefCell::new(None::<CcRc<Node>>), 0u8));
+    let n = node();
+    *a.0.borrow_mut() = Some(n.clone());
+    *n.next.borrow_mut() = Some(n.clone());
+    drop(n);
+    assert_eq!(collect_cycles(), 0);
+    drop(a);
+    assert_eq!(collect_cycles(), 1);
+}
+
+#[test]
+fn skipped_fields_need_not_be_trace() {
+    // Instant isn't Trace, which is fine since U is only used by a skipped field
+    let a = CcRc::new(Pair(
+        RefCell::new(None::<CcRc<Node>>),
+        std::time::Instant::now(),
+    ));
+    let n = node();
+    *a.0.borrow_mut() = Some(n.clone());
+    *n.next.borrow_mut() = Some(n.clone());
+    drop(n);
+    assert_eq!(collect_cycles(), 0);
+    drop(a);
+    assert_eq!(collect_cycles(), 1);
+}
+
+#[derive(Trace)]
+struct Shared {
+    // not traced through, even without #[trace(skip)]
+    config: Rc<RefCell<Option<CcRc<Shared>>>>,
+    next: RefCell<Option<CcRc<Shared>>>,
+}
+
+#[test]
+fn rc_fields_are_not_traced() {
+    let config = Rc::new(RefCell::new(None));
+    let a = CcRc::new(Shared {
+        config: config.clone(),
+        next: RefCell::new(None),
+    });
+    *a.next.borrow_mut() = Some(a.clone());
+    *config.borrow_mut() = Some(a.clone());
+    drop(a);
+    // the CcRc inside the Rc counts as a reference from outside
+    assert_eq!(collect_cycles(), 0);
+    config.borrow_mut().take();
+    assert_eq!(collect_cycles(), 1);
+}
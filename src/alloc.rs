@@ -0,0 +1,219 @@
+// Rc allocates its RcInner through an Allocator. By default that's Global,
+// the same heap Box and Vec use, but Rc::new_in() takes any other Allocator,
+// e.g. a &Bump so that a whole tree of Rcs lives in one arena.
+//
+// This is a small stand-in for the unstable std::alloc::Allocator trait: just
+// enough to hand out and take back memory for a given Layout.
+
+use crate::cell::Cell;
+use crate::refcell::RefCell;
+use std::alloc::{self, Layout};
+use std::error::Error;
+use std::fmt;
+use std::ptr::{self, NonNull};
+
+/// Returned by Allocator::allocate() when the memory couldn't be allocated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "memory allocation failed")
+    }
+}
+
+impl Error for AllocError {}
+
+/// # Safety
+/// Memory returned by allocate() must be valid for `layout` and stay valid
+/// until it's passed to deallocate() on the same allocator (or a copy of it),
+/// or until the allocator and all its copies are dropped, whichever comes
+/// first. So a user has to keep the allocator alive for as long as it uses the
+/// memory, which Rc<T, &Bump> does by borrowing it.
+pub unsafe trait Allocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError>;
+
+    /// # Safety
+    /// `ptr` must come from allocate() on this allocator, with this `layout`.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+
+    // Whether this is Global (or a reference to it). The debug-rc-leaks
+    // registry only keeps track of Global allocations: any other allocator may
+    // free its memory when it's dropped, e.g. Bump, and then the registry would
+    // be left pointing at freed memory. Not meant to be overridden.
+    #[doc(hidden)]
+    fn is_global(&self) -> bool {
+        false
+    }
+}
+
+unsafe impl<A: Allocator + ?Sized> Allocator for &A {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        (**self).allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        (**self).deallocate(ptr, layout)
+    }
+
+    fn is_global(&self) -> bool {
+        (**self).is_global()
+    }
+}
+
+/// The global heap, i.e. the same memory Box::new() hands out
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        // std::alloc::alloc() doesn't allow zero sized layouts
+        if layout.size() == 0 {
+            return Ok(dangling(layout));
+        }
+        NonNull::new(unsafe { alloc::alloc(layout) }).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            alloc::dealloc(ptr.as_ptr(), layout)
+        }
+    }
+
+    fn is_global(&self) -> bool {
+        true
+    }
+}
+
+// A non-null, well aligned pointer for zero sized allocations
+fn dangling(layout: Layout) -> NonNull<u8> {
+    // align is a power of two, so never 0
+    NonNull::new(layout.align() as *mut u8).unwrap()
+}
+
+// Bump hands out memory by bumping a pointer through a chunk of memory, and
+// grabs a new chunk from the global heap when the current one is used up.
+// deallocate() does nothing; everything is freed at once when the Bump itself
+// is dropped. Anything allocated in a &Bump borrows it, so the borrow checker
+// makes sure it's all gone by then.
+// Like Cell and RefCell, Bump is !Sync, the bookkeeping isn't atomic.
+
+/// A bump/arena allocator that frees all its memory at once when dropped
+pub struct Bump {
+    chunk_size: usize,
+    // next free byte and the end of the current chunk, both null to begin with
+    ptr: Cell<*mut u8>,
+    end: Cell<*mut u8>,
+    chunks: RefCell<Vec<(NonNull<u8>, Layout)>>,
+}
+
+impl Bump {
+    pub fn new() -> Self {
+        Self::with_chunk_size(4096)
+    }
+
+    /// Creates a Bump that allocates its memory `chunk_size` bytes at a time.
+    /// Allocations bigger than that get a chunk of their own.
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        Self {
+            chunk_size,
+            ptr: Cell::new(ptr::null_mut()),
+            end: Cell::new(ptr::null_mut()),
+            chunks: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Number of chunks allocated from the global heap so far
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.borrow().len()
+    }
+
+    fn new_chunk(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let size = self.chunk_size.max(layout.size());
+        let chunk_layout = Layout::from_size_align(size, layout.align()).map_err(|_| AllocError)?;
+        let chunk = Global.allocate(chunk_layout)?;
+        self.chunks.borrow_mut().push((chunk, chunk_layout));
+        let start = chunk.as_ptr();
+        // layout.size() <= size, so both stay within the chunk
+        self.ptr.set(unsafe { start.add(layout.size()) });
+        self.end.set(unsafe { start.add(size) });
+        Ok(chunk)
+    }
+}
+
+impl Default for Bump {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl Allocator for Bump {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let ptr = self.ptr.get();
+        if ptr.is_null() {
+            return self.new_chunk(layout);
+        }
+        let available = self.end.get() as usize - ptr as usize;
+        let pad = ptr.align_offset(layout.align());
+        match pad.checked_add(layout.size()) {
+            Some(needed) if needed <= available => {
+                // ptr + needed <= end, still inside the current chunk
+                let start = unsafe { ptr.add(pad) };
+                self.ptr.set(unsafe { start.add(layout.size()) });
+                Ok(unsafe { NonNull::new_unchecked(start) })
+            }
+            _ => self.new_chunk(layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
+        // freed all at once in Drop
+    }
+}
+
+impl Drop for Bump {
+    fn drop(&mut self) {
+        for (chunk, layout) in self.chunks.borrow_mut().drain(..) {
+            unsafe { Global.deallocate(chunk, layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Allocator, Bump};
+    use std::alloc::Layout;
+
+    #[test]
+    fn bump_alignment_and_chunks() {
+        let bump = Bump::with_chunk_size(64);
+        let a = bump.allocate(Layout::new::<u8>()).unwrap();
+        let b = bump.allocate(Layout::new::<u64>()).unwrap();
+        assert_eq!(0, b.as_ptr() as usize % 8);
+        assert!(b.as_ptr() as usize > a.as_ptr() as usize);
+        assert_eq!(1, bump.chunk_count());
+
+        // doesn't fit in what's left of the first chunk
+        let big = bump
+            .allocate(Layout::from_size_align(100, 32).unwrap())
+            .unwrap();
+        assert_eq!(0, big.as_ptr() as usize % 32);
+        assert_eq!(2, bump.chunk_count());
+    }
+
+    #[test]
+    fn bump_memory_is_usable() {
+        let bump = Bump::with_chunk_size(32);
+        let ptrs: Vec<_> = (0..20u64)
+            .map(|i| {
+                let p = bump.allocate(Layout::new::<u64>()).unwrap().cast::<u64>();
+                unsafe { p.as_ptr().write(i) };
+                p
+            })
+            .collect();
+        for (i, p) in ptrs.iter().enumerate() {
+            assert_eq!(i as u64, unsafe { *p.as_ptr() });
+        }
+        assert_eq!(5, bump.chunk_count());
+    }
+}
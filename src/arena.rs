@@ -0,0 +1,277 @@
+// Arena is an alternative to graphs of Rc<RefCell<T>>: all the values live in
+// one Vec owned by the Arena, and they refer to each other through Index
+// handles instead of pointers. An Index is just a number, so it's Copy and a
+// cycle of them can't leak anything. Whatever is still in the arena is
+// dropped with it.
+//
+// Each slot is a RefCell<T>, so a value can be borrowed (mutably) through a
+// &Arena, with the same run time checks as RefCell: mutably borrowing two
+// different slots at once is fine, borrowing one slot twice mutably isn't.
+// Inserting and removing need a &mut Arena, so no borrow can be outstanding
+// while a slot changes.
+//
+// Removing a value frees its slot for reuse, and an Index to the old value
+// must not silently point at the new one. So every slot has a generation that
+// goes up each time its value is removed, and an Index remembers the
+// generation it was created in. An Index whose generation doesn't match is
+// stale, and using it fails with ArenaError::Stale.
+// An Index from a different Arena isn't detected, it just refers to whatever
+// is at that position in this one.
+
+use crate::refcell::{BorrowError, BorrowMutError, Ref, RefCell, RefMut};
+use std::error::Error;
+use std::fmt;
+
+/// A handle to a value in an Arena
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Index {
+    slot: usize,
+    generation: u64,
+}
+
+/// Returned by Arena::try_borrow() and Arena::try_borrow_mut()
+#[derive(Debug)]
+pub enum ArenaError<E> {
+    /// The value the Index referred to has been removed
+    Stale,
+    /// The value is already borrowed
+    Borrow(E),
+}
+
+impl<E: fmt::Display> fmt::Display for ArenaError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArenaError::Stale => write!(f, "stale arena index"),
+            ArenaError::Borrow(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> Error for ArenaError<E> {}
+
+enum Entry<T> {
+    Occupied(RefCell<T>),
+    // free slots form a linked list, so insert() can find one right away
+    Free { next_free: Option<usize> },
+}
+
+struct Slot<T> {
+    generation: u64,
+    entry: Entry<T>,
+}
+
+pub struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    free_head: Option<usize>,
+    len: usize,
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Arena {
+            slots: Vec::new(),
+            free_head: None,
+            len: 0,
+        }
+    }
+
+    /// Number of values in the arena
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Moves `val` into the arena, returning the Index to get to it
+    pub fn insert(&mut self, val: T) -> Index {
+        self.len += 1;
+        match self.free_head {
+            Some(slot) => {
+                let free = &mut self.slots[slot];
+                if let Entry::Free { next_free } = free.entry {
+                    self.free_head = next_free;
+                }
+                free.entry = Entry::Occupied(RefCell::new(val));
+                Index {
+                    slot,
+                    generation: free.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    entry: Entry::Occupied(RefCell::new(val)),
+                });
+                Index {
+                    slot: self.slots.len() - 1,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    /// Removes the value at `idx` from the arena and returns it, or returns
+    /// None if `idx` is stale. Every Index to the value becomes stale.
+    pub fn remove(&mut self, idx: Index) -> Option<T> {
+        self.cell(idx)?;
+        let slot = &mut self.slots[idx.slot];
+        let entry = std::mem::replace(
+            &mut slot.entry,
+            Entry::Free {
+                next_free: self.free_head,
+            },
+        );
+        slot.generation += 1;
+        self.free_head = Some(idx.slot);
+        self.len -= 1;
+        match entry {
+            Entry::Occupied(cell) => Some(cell.into_inner()),
+            Entry::Free { .. } => unreachable!(),
+        }
+    }
+
+    /// Whether `idx` still refers to a value in the arena
+    pub fn contains(&self, idx: Index) -> bool {
+        self.cell(idx).is_some()
+    }
+
+    fn cell(&self, idx: Index) -> Option<&RefCell<T>> {
+        match self.slots.get(idx.slot) {
+            Some(Slot {
+                generation,
+                entry: Entry::Occupied(cell),
+            }) if *generation == idx.generation => Some(cell),
+            _ => None,
+        }
+    }
+
+    /// Immutably borrows the value at `idx`, panicking if `idx` is stale or
+    /// the value is mutably borrowed.
+    #[track_caller]
+    pub fn borrow(&self, idx: Index) -> Ref<'_, T> {
+        match self.try_borrow(idx) {
+            Ok(r) => r,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Mutably borrows the value at `idx`, panicking if `idx` is stale or the
+    /// value is borrowed at all.
+    #[track_caller]
+    pub fn borrow_mut(&self, idx: Index) -> RefMut<'_, T> {
+        match self.try_borrow_mut(idx) {
+            Ok(r) => r,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_borrow(&self, idx: Index) -> Result<Ref<'_, T>, ArenaError<BorrowError>> {
+        let cell = self.cell(idx).ok_or(ArenaError::Stale)?;
+        cell.try_borrow().map_err(ArenaError::Borrow)
+    }
+
+    #[track_caller]
+    pub fn try_borrow_mut(&self, idx: Index) -> Result<RefMut<'_, T>, ArenaError<BorrowMutError>> {
+        let cell = self.cell(idx).ok_or(ArenaError::Stale)?;
+        cell.try_borrow_mut().map_err(ArenaError::Borrow)
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Node {
+        name: &'static str,
+        parent: Option<Index>,
+        children: Vec<Index>,
+    }
+
+    #[test]
+    fn tree_of_indices() {
+        let mut arena = Arena::new();
+        let root = arena.insert(Node {
+            name: "root",
+            parent: None,
+            children: vec![],
+        });
+        let child = arena.insert(Node {
+            name: "child",
+            parent: Some(root),
+            children: vec![],
+        });
+        arena.borrow_mut(root).children.push(child);
+        assert_eq!(arena.len(), 2);
+
+        // a child and its parent can be borrowed mutably at the same time
+        let mut c = arena.borrow_mut(child);
+        let mut p = arena.borrow_mut(c.parent.unwrap());
+        c.name = "leaf";
+        p.name = "trunk";
+        drop((c, p));
+        assert_eq!(arena.borrow(arena.borrow(root).children[0]).name, "leaf");
+        assert_eq!(arena.borrow(root).name, "trunk");
+    }
+
+    #[test]
+    fn conflicting_borrows_are_errors() {
+        let mut arena = Arena::new();
+        let idx = arena.insert(5);
+        let r = arena.borrow(idx);
+        assert!(matches!(
+            arena.try_borrow_mut(idx),
+            Err(ArenaError::Borrow(_))
+        ));
+        assert!(arena.try_borrow(idx).is_ok());
+        drop(r);
+        let w = arena.borrow_mut(idx);
+        assert!(matches!(arena.try_borrow(idx), Err(ArenaError::Borrow(_))));
+        drop(w);
+    }
+
+    #[test]
+    #[should_panic(expected = "already mutably borrowed")]
+    fn borrow_while_mutably_borrowed_panics() {
+        let mut arena = Arena::new();
+        let idx = arena.insert(5);
+        let _w = arena.borrow_mut(idx);
+        arena.borrow(idx);
+    }
+
+    #[test]
+    fn removed_indices_are_stale() {
+        let mut arena = Arena::new();
+        let a = arena.insert(String::from("a"));
+        let b = arena.insert(String::from("b"));
+        assert_eq!(arena.remove(a).as_deref(), Some("a"));
+        assert!(!arena.contains(a));
+        assert_eq!(arena.remove(a), None);
+        assert!(matches!(arena.try_borrow(a), Err(ArenaError::Stale)));
+
+        // the slot is reused, but the old Index doesn't see the new value
+        let c = arena.insert(String::from("c"));
+        assert_ne!(a, c);
+        assert!(matches!(arena.try_borrow_mut(a), Err(ArenaError::Stale)));
+        assert_eq!(*arena.borrow(c), "c");
+        assert_eq!(*arena.borrow(b), "b");
+        assert_eq!(arena.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "stale arena index")]
+    fn borrowing_stale_index_panics() {
+        let mut arena = Arena::new();
+        let idx = arena.insert(());
+        arena.remove(idx);
+        arena.borrow_mut(idx);
+    }
+}
@@ -0,0 +1,727 @@
+// CcRc is an Rc that can also reclaim cycles of strong references, using the
+// synchronous cycle collector from Bacon & Rajan, "Concurrent Cycle Collection
+// in Reference Counted Systems" (2001).
+// Counting works exactly like rc::Rc: the value is dropped as soon as its
+// count hits 0. A cycle never gets there on its own though, so whenever a
+// count is decremented to something other than 0, the allocation becomes a
+// "possible root" of a garbage cycle and is remembered in a per thread roots
+// buffer. collect_cycles() then does trial deletion starting from those roots:
+// - mark_gray() subtracts every reference that comes from inside the graph
+//   reachable from a root. Whatever still has a count > 0 afterwards must be
+//   referenced from outside, e.g. by a CcRc on the stack.
+// - scan() turns those, and everything reachable from them, back to black and
+//   restores their counts. The rest is white: only referenced by other white
+//   allocations, i.e. garbage.
+// - collect_white() gathers the white allocations, which are then dropped.
+// Finding the references inside a value is what the Trace trait is for.
+//
+// Unlike the paper we can't just free white allocations: dropping their values
+// runs arbitrary Drop code, which drops CcRcs into other white allocations and
+// could even move one of them somewhere else. So before dropping anything, the
+// counts of the white allocations are restored to their real values, plus one
+// extra reference held by the collector itself. Then every white value is
+// marked Dead and dropped, and finally the collector's references are released,
+// which frees each allocation unless a Drop impl kept a CcRc to it. Dereferencing
+// a CcRc to a Dead value panics instead of reading a dropped value.
+//
+// Like Rc, CcRc is !Send + !Sync, so the roots buffer is a thread_local.
+
+use crate::alloc::Allocator;
+use crate::cell::Cell;
+use crate::rc::Rc;
+use crate::refcell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+/// `#[derive(Trace)]`, see the UnsafeCells-derive crate
+#[cfg(feature = "derive")]
+pub use UnsafeCells_derive::Trace;
+
+/// Types that can report every CcRc they own to the cycle collector.
+///
+/// # Safety
+/// trace() must visit each CcRc that is owned by self exactly once, and
+/// nothing else. Visiting a CcRc that self doesn't own makes the collector
+/// drop values that are still in use. Missing one only means the collector
+/// may not reclaim a cycle through it.
+/// trace() must not create, clone or drop any CcRc, and must report the same
+/// CcRcs every time it is called during one collect_cycles() call.
+pub unsafe trait Trace {
+    fn trace(&self, tracer: &mut Tracer<'_>);
+}
+
+/// Passed to Trace::trace() to visit the CcRcs a value owns
+pub struct Tracer<'a> {
+    visit: &'a mut dyn FnMut(BoxPtr),
+}
+
+impl Tracer<'_> {
+    pub fn visit<T: Trace + 'static>(&mut self, cc: &CcRc<T>) {
+        (self.visit)(cc.inner)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    // in use, or not looked at by the collector yet
+    Black,
+    // visited by mark_gray(), count holds only the references from outside
+    Gray,
+    // garbage, unless scan() finds out otherwise
+    White,
+    // possible root of a cycle
+    Purple,
+    // the value has been dropped, only the allocation is left
+    Dead,
+}
+
+struct CcBox<T: ?Sized> {
+    ref_count: Cell<usize>,
+    color: Cell<Color>,
+    // whether this allocation is in the roots buffer. A buffered allocation is
+    // only freed by collect_cycles(), so the buffer never dangles.
+    buffered: Cell<bool>,
+    val: ManuallyDrop<T>,
+}
+
+type BoxPtr = NonNull<CcBox<dyn Trace>>;
+
+// The roots buffer. When the thread exits, anything still in it is either
+// freed, if its count already went to 0, or unbuffered, so that the last CcRc
+// to it frees it as usual.
+struct Roots(RefCell<Vec<BoxPtr>>);
+
+impl Drop for Roots {
+    fn drop(&mut self) {
+        for ptr in self.0.borrow_mut().drain(..) {
+            let inner = unsafe { ptr.as_ref() };
+            inner.buffered.set(false);
+            if inner.ref_count.get() == 0 {
+                unsafe { free(ptr) };
+            }
+        }
+    }
+}
+
+thread_local! {
+    static ROOTS: Roots = Roots(RefCell::new(Vec::new()));
+}
+
+// Frees the allocation. The value must have been dropped already, and since
+// val is a ManuallyDrop, dropping the Box won't drop it again.
+unsafe fn free<T: ?Sized>(ptr: NonNull<CcBox<T>>) {
+    drop(Box::from_raw(ptr.as_ptr()));
+}
+
+// Called when a count is decremented to something other than 0
+fn possible_root(ptr: BoxPtr) {
+    let inner = unsafe { ptr.as_ref() };
+    if inner.color.get() == Color::Purple {
+        return;
+    }
+    inner.color.set(Color::Purple);
+    if !inner.buffered.get() {
+        // during thread exit the buffer may be gone already, in which case the
+        // allocation just isn't considered for collection
+        let pushed = ROOTS.try_with(|roots| roots.0.borrow_mut().push(ptr));
+        inner.buffered.set(pushed.is_ok());
+    }
+}
+
+// Calls `f` on every allocation that `ptr`'s value holds a CcRc to, except for
+// Dead ones. A Dead allocation can still be reachable, through a CcRc that a
+// Drop impl kept hold of and stored somewhere live, but its value is gone, so
+// there's nothing to trace in it and it can't be part of a cycle. Skipping it
+// here keeps every phase from recoloring it or touching its count.
+fn for_each_child(ptr: BoxPtr, mut f: impl FnMut(BoxPtr)) {
+    let inner = unsafe { ptr.as_ref() };
+    debug_assert!(inner.color.get() != Color::Dead);
+    inner.val.trace(&mut Tracer {
+        visit: &mut |child: BoxPtr| {
+            if unsafe { child.as_ref() }.color.get() != Color::Dead {
+                f(child);
+            }
+        },
+    });
+}
+
+// The phases below walk the graph with an explicit stack rather than by
+// recursion: the graph can be as deep as the heap of an interpreter, and a
+// ring of a few ten thousand CcRcs would overflow the call stack.
+
+fn mark_gray(root: BoxPtr) {
+    let mut stack = vec![root];
+    while let Some(ptr) = stack.pop() {
+        let inner = unsafe { ptr.as_ref() };
+        if !matches!(inner.color.get(), Color::Gray | Color::Dead) {
+            inner.color.set(Color::Gray);
+            for_each_child(ptr, |child| {
+                let child_inner = unsafe { child.as_ref() };
+                child_inner.ref_count.set(child_inner.ref_count.get() - 1);
+                stack.push(child);
+            });
+        }
+    }
+}
+
+fn scan(root: BoxPtr) {
+    let mut stack = vec![root];
+    while let Some(ptr) = stack.pop() {
+        let inner = unsafe { ptr.as_ref() };
+        if inner.color.get() == Color::Gray {
+            if inner.ref_count.get() > 0 {
+                scan_black(ptr);
+            } else {
+                inner.color.set(Color::White);
+                for_each_child(ptr, |child| stack.push(child));
+            }
+        }
+    }
+}
+
+fn scan_black(root: BoxPtr) {
+    unsafe { root.as_ref() }.color.set(Color::Black);
+    let mut stack = vec![root];
+    while let Some(ptr) = stack.pop() {
+        for_each_child(ptr, |child| {
+            let child_inner = unsafe { child.as_ref() };
+            child_inner.ref_count.set(child_inner.ref_count.get() + 1);
+            if child_inner.color.get() != Color::Black {
+                child_inner.color.set(Color::Black);
+                stack.push(child);
+            }
+        });
+    }
+}
+
+fn collect_white(root: BoxPtr, white: &mut Vec<BoxPtr>) {
+    let mut stack = vec![root];
+    while let Some(ptr) = stack.pop() {
+        let inner = unsafe { ptr.as_ref() };
+        if inner.color.get() == Color::White && !inner.buffered.get() {
+            inner.color.set(Color::Black);
+            for_each_child(ptr, |child| stack.push(child));
+            white.push(ptr);
+        }
+    }
+}
+
+/// Looks for cycles of CcRcs on this thread that are no longer reachable from
+/// outside the cycle and drops them. Returns how many values were dropped.
+pub fn collect_cycles() -> usize {
+    // Take the whole buffer: anything that becomes a possible root while we
+    // are running goes into a fresh one, for the next collection.
+    let roots = ROOTS
+        .try_with(|roots| mem::take(&mut *roots.0.borrow_mut()))
+        .unwrap_or_default();
+
+    // mark_roots
+    let mut candidates = Vec::new();
+    for ptr in roots {
+        let inner = unsafe { ptr.as_ref() };
+        if inner.color.get() == Color::Purple {
+            mark_gray(ptr);
+            candidates.push(ptr);
+        } else {
+            // either marked gray already through another root, or its count
+            // went to 0 (and its value was dropped) while it was buffered, in
+            // which case freeing it was left to us
+            inner.buffered.set(false);
+            if inner.color.get() == Color::Dead && inner.ref_count.get() == 0 {
+                unsafe { free(ptr) };
+            }
+        }
+    }
+    // scan_roots
+    for &ptr in &candidates {
+        scan(ptr);
+    }
+    // collect_roots
+    let mut white = Vec::new();
+    for ptr in candidates {
+        unsafe { ptr.as_ref() }.buffered.set(false);
+        collect_white(ptr, &mut white);
+    }
+
+    // Put back the references between white allocations that mark_gray()
+    // subtracted, and take one more for the collector itself
+    for &ptr in &white {
+        for_each_child(ptr, |child| {
+            let child_inner = unsafe { child.as_ref() };
+            child_inner.ref_count.set(child_inner.ref_count.get() + 1);
+        });
+    }
+    for &ptr in &white {
+        let inner = unsafe { ptr.as_ref() };
+        inner.ref_count.set(inner.ref_count.get() + 1);
+        inner.color.set(Color::Dead);
+    }
+    // If a Drop impl panics, the rest of the cycle just leaks
+    for &ptr in &white {
+        unsafe { ManuallyDrop::drop(&mut (*ptr.as_ptr()).val) };
+    }
+    for &ptr in &white {
+        release(ptr);
+    }
+    white.len()
+}
+
+// Gives up one strong reference to `ptr`, dropping the value and freeing the
+// allocation if it was the last one
+fn release(ptr: BoxPtr) {
+    let inner = unsafe { ptr.as_ref() };
+    let count = inner.ref_count.get() - 1;
+    inner.ref_count.set(count);
+    if count == 0 {
+        if inner.color.get() != Color::Dead {
+            inner.color.set(Color::Dead);
+            unsafe { ManuallyDrop::drop(&mut (*ptr.as_ptr()).val) };
+        }
+        if !inner.buffered.get() {
+            unsafe { free(ptr) };
+        }
+    } else if inner.color.get() != Color::Dead {
+        possible_root(ptr);
+    }
+}
+
+/// A single-threaded reference counted pointer whose cycles can be reclaimed
+/// with collect_cycles().
+pub struct CcRc<T: Trace + 'static> {
+    inner: NonNull<CcBox<T>>,
+    phantom: PhantomData<CcBox<T>>,
+}
+
+impl<T: Trace + 'static> CcRc<T> {
+    pub fn new(val: T) -> Self {
+        let inner = Box::new(CcBox {
+            ref_count: Cell::new(1),
+            color: Cell::new(Color::Black),
+            buffered: Cell::new(false),
+            val: ManuallyDrop::new(val),
+        });
+        CcRc {
+            inner: NonNull::from(Box::leak(inner)),
+            phantom: PhantomData,
+        }
+    }
+
+    fn inner(&self) -> &CcBox<T> {
+        unsafe { self.inner.as_ref() }
+    }
+
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().ref_count.get()
+    }
+
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.inner == other.inner
+    }
+
+    /// Returns false if the value was dropped by collect_cycles(). That only
+    /// happens to a CcRc that a Drop impl inside a collected cycle kept hold
+    /// of, and dereferencing it panics.
+    pub fn is_alive(this: &Self) -> bool {
+        this.inner().color.get() != Color::Dead
+    }
+}
+
+impl<T: Trace + 'static> Clone for CcRc<T> {
+    fn clone(&self) -> Self {
+        let count = &self.inner().ref_count;
+        if count.get() == usize::MAX {
+            std::process::abort();
+        }
+        count.set(count.get() + 1);
+        CcRc {
+            inner: self.inner,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: Trace + 'static> Deref for CcRc<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        let inner = self.inner();
+        assert!(
+            inner.color.get() != Color::Dead,
+            "CcRc value was dropped by the cycle collector"
+        );
+        &inner.val
+    }
+}
+
+impl<T: Trace + 'static> Drop for CcRc<T> {
+    fn drop(&mut self) {
+        release(self.inner);
+    }
+}
+
+impl<T: Trace + fmt::Debug + 'static> fmt::Debug for CcRc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if CcRc::is_alive(self) {
+            fmt::Debug::fmt(&**self, f)
+        } else {
+            f.write_str("(collected)")
+        }
+    }
+}
+
+unsafe impl<T: Trace + 'static> Trace for CcRc<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        tracer.visit(self);
+    }
+}
+
+// Types that can't own a CcRc have nothing to trace
+macro_rules! trace_nothing {
+    ($($t:ty),*) => {
+        $(
+            unsafe impl Trace for $t {
+                fn trace(&self, _tracer: &mut Tracer<'_>) {}
+            }
+        )*
+    };
+}
+
+trace_nothing!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    String,
+    &'static str
+);
+
+// A Copy type can't own a CcRc, since CcRc isn't Copy
+unsafe impl<T: Copy> Trace for Cell<T> {
+    fn trace(&self, _tracer: &mut Tracer<'_>) {}
+}
+
+// A RefCell that is mutably borrowed while collecting is skipped. Its owner
+// can't be garbage then anyway: the RefMut was borrowed from some CcRc that
+// is still alive.
+unsafe impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        if let Ok(val) = self.try_borrow() {
+            val.trace(tracer);
+        }
+    }
+}
+
+// An Rc can be shared by several owners, so tracing through it would report
+// the CcRcs inside once per owner and make the collector drop them while
+// they're still in use. Not tracing it only means a cycle through an Rc isn't
+// collected.
+unsafe impl<T: ?Sized, A: Allocator> Trace for Rc<T, A> {
+    fn trace(&self, _tracer: &mut Tracer<'_>) {}
+}
+
+unsafe impl<T: Trace + ?Sized> Trace for Box<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        (**self).trace(tracer);
+    }
+}
+
+unsafe impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        if let Some(val) = self {
+            val.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<T: Trace> Trace for [T] {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        for val in self {
+            val.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        self[..].trace(tracer);
+    }
+}
+
+unsafe impl<T: Trace> Trace for VecDeque<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        for val in self {
+            val.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<K: Trace, V: Trace, S> Trace for HashMap<K, V, S> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        for (key, val) in self {
+            key.trace(tracer);
+            val.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<T: Trace, S> Trace for HashSet<T, S> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        for val in self {
+            val.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<K: Trace, V: Trace> Trace for BTreeMap<K, V> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        for (key, val) in self {
+            key.trace(tracer);
+            val.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<A: Trace, B: Trace> Trace for (A, B) {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        self.0.trace(tracer);
+        self.1.trace(tracer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    thread_local! {
+        static KEPT: RefCell<Option<CcRc<Node>>> = RefCell::new(None);
+    }
+
+    struct Node {
+        edges: RefCell<Vec<CcRc<Node>>>,
+        drops: Rc<Cell<usize>>,
+        // stash a clone of the first edge in KEPT when dropped
+        keep_on_drop: bool,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer<'_>) {
+            self.edges.trace(tracer);
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+            if self.keep_on_drop {
+                let first = self.edges.borrow()[0].clone();
+                KEPT.with(|kept| *kept.borrow_mut() = Some(first));
+            }
+        }
+    }
+
+    fn node(drops: &Rc<Cell<usize>>) -> CcRc<Node> {
+        CcRc::new(Node {
+            edges: RefCell::new(Vec::new()),
+            drops: drops.clone(),
+            keep_on_drop: false,
+        })
+    }
+
+    fn link(from: &CcRc<Node>, to: &CcRc<Node>) {
+        from.edges.borrow_mut().push(to.clone());
+    }
+
+    #[test]
+    fn acyclic_values_are_dropped_without_collecting() {
+        let drops = Rc::new(Cell::new(0));
+        let a = node(&drops);
+        link(&a, &node(&drops));
+        drop(a);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(collect_cycles(), 0);
+    }
+
+    #[test]
+    fn collects_cycle() {
+        let drops = Rc::new(Cell::new(0));
+        let a = node(&drops);
+        let b = node(&drops);
+        link(&a, &b);
+        link(&b, &a);
+        drop((a, b));
+        assert_eq!(drops.get(), 0);
+        assert_eq!(collect_cycles(), 2);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(collect_cycles(), 0);
+    }
+
+    #[test]
+    fn collects_self_cycle() {
+        let drops = Rc::new(Cell::new(0));
+        let a = node(&drops);
+        link(&a, &a);
+        drop(a);
+        assert_eq!(collect_cycles(), 1);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn reachable_cycle_is_kept() {
+        let drops = Rc::new(Cell::new(0));
+        let a = node(&drops);
+        let b = node(&drops);
+        link(&a, &b);
+        link(&b, &a);
+        drop(b);
+        assert_eq!(collect_cycles(), 0);
+        assert_eq!(drops.get(), 0);
+        // the counts are restored after the trial deletion
+        assert_eq!(CcRc::strong_count(&a), 2);
+        assert_eq!(CcRc::strong_count(&a.edges.borrow()[0]), 1);
+
+        drop(a);
+        assert_eq!(collect_cycles(), 2);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn live_values_referenced_by_a_cycle_survive_it() {
+        let drops = Rc::new(Cell::new(0));
+        let a = node(&drops);
+        let b = node(&drops);
+        let live = node(&drops);
+        let tail = node(&drops);
+        link(&a, &b);
+        link(&b, &a);
+        link(&b, &live);
+        link(&b, &tail);
+        drop((a, b, tail));
+        assert_eq!(collect_cycles(), 3);
+        assert_eq!(drops.get(), 3);
+        assert_eq!(CcRc::strong_count(&live), 1);
+        assert!(CcRc::is_alive(&live));
+    }
+
+    #[test]
+    fn ccrc_kept_by_a_drop_impl_is_dead() {
+        let drops = Rc::new(Cell::new(0));
+        let a = CcRc::new(Node {
+            edges: RefCell::new(Vec::new()),
+            drops: drops.clone(),
+            keep_on_drop: true,
+        });
+        let b = node(&drops);
+        link(&a, &b);
+        link(&b, &a);
+        drop((a, b));
+        assert_eq!(collect_cycles(), 2);
+
+        // a's Drop kept b alive, but b's value was dropped with the cycle
+        let kept = KEPT.with(|kept| kept.borrow_mut().take()).unwrap();
+        assert!(!CcRc::is_alive(&kept));
+        assert_eq!(CcRc::strong_count(&kept), 1);
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| kept.edges.borrow().len())).is_err());
+        drop(kept);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn dead_ccrc_in_a_live_node_is_left_alone() {
+        let drops = Rc::new(Cell::new(0));
+        let a = CcRc::new(Node {
+            edges: RefCell::new(Vec::new()),
+            drops: drops.clone(),
+            keep_on_drop: true,
+        });
+        let b = node(&drops);
+        link(&a, &b);
+        link(&b, &a);
+        drop((a, b));
+        assert_eq!(collect_cycles(), 2);
+        let kept = KEPT.with(|kept| kept.borrow_mut().take()).unwrap();
+
+        // a live node that traces to the dead one
+        let holder = node(&drops);
+        holder.edges.borrow_mut().push(kept);
+        // make holder a possible root
+        drop(holder.clone());
+        assert_eq!(collect_cycles(), 0);
+        assert_eq!(collect_cycles(), 0);
+        let dead = holder.edges.borrow()[0].clone();
+        assert!(!CcRc::is_alive(&dead));
+        assert_eq!(CcRc::strong_count(&dead), 2);
+        drop(dead);
+
+        // once holder is garbage itself, the dead allocation goes with it
+        link(&holder, &holder);
+        drop(holder);
+        assert_eq!(collect_cycles(), 1);
+        assert_eq!(collect_cycles(), 0);
+        assert_eq!(drops.get(), 3);
+    }
+
+    // A ring node that counts its drops in a thread local, since a shared
+    // Rc<Cell<usize>> counter would overflow the test limit on Rc's count
+    struct Link {
+        next: RefCell<Option<CcRc<Link>>>,
+    }
+
+    thread_local! {
+        static LINK_DROPS: Cell<usize> = Cell::new(0);
+    }
+
+    unsafe impl Trace for Link {
+        fn trace(&self, tracer: &mut Tracer<'_>) {
+            self.next.trace(tracer);
+        }
+    }
+
+    impl Drop for Link {
+        fn drop(&mut self) {
+            LINK_DROPS.with(|drops| drops.set(drops.get() + 1));
+        }
+    }
+
+    #[test]
+    fn long_ring_does_not_overflow_the_stack() {
+        const LEN: usize = 100_000;
+        let new_link = || {
+            CcRc::new(Link {
+                next: RefCell::new(None),
+            })
+        };
+        let first = new_link();
+        let mut last = first.clone();
+        for _ in 1..LEN {
+            let next = new_link();
+            *last.next.borrow_mut() = Some(next.clone());
+            last = next;
+        }
+        *last.next.borrow_mut() = Some(first.clone());
+        drop(last);
+
+        // reachable through `first`, so the whole ring is scanned back to black
+        assert_eq!(collect_cycles(), 0);
+        assert_eq!(CcRc::strong_count(&first), 2);
+        drop(first);
+        assert_eq!(collect_cycles(), LEN);
+        assert_eq!(LINK_DROPS.with(Cell::get), LEN);
+    }
+}
@@ -3,23 +3,18 @@ A cell is a container with interior mutability such that it differs from
 Refcell by only giving out bitwise copies of the interior data structure.
  */
 
-#[cfg(test)]
-mod tests {
-    use super::Cell;
-    #[test]
-    fn mutate_unsafe() {
-        let x = Cell::new(6);
-        x.set(7);
-        assert_eq!(7, x.get());
-    }
-
-    #[test]
-    fn some_test() {
-        assert!(true);
-    }
-}
 use std::cell::UnsafeCell;
-pub struct Cell<T> {
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem;
+use std::ptr;
+
+// repr(transparent) guarantees that Cell<T> has the same memory layout as
+// UnsafeCell<T>, which in turn has the same layout as T. That's what lets us
+// go from a &mut T to a &Cell<T> in from_mut() and from &Cell<[T]> to
+// &[Cell<T>] in as_slice_of_cells() with a plain pointer cast.
+#[repr(transparent)]
+pub struct Cell<T: ?Sized> {
     val: UnsafeCell<T>,
 }
 impl<T> Cell<T> {
@@ -30,7 +25,9 @@ impl<T> Cell<T> {
     }
     pub fn set(&self, val: T) {
         // mutate the interior of the cell
-        unsafe { *self.val.get() = val };
+        // replace() so that the old value is dropped only after we're done
+        // writing, in case its Drop impl has a reference back to this cell
+        drop(self.replace(val));
     }
     pub fn get(&self) -> T
     where
@@ -38,6 +35,192 @@ impl<T> Cell<T> {
     {
         unsafe { *self.val.get() }
     }
+
+    /// Puts `val` in the cell and hands back the value that was there before
+    pub fn replace(&self, val: T) -> T {
+        // we never give out references into the cell, and Cell is !Sync, so no
+        // one else can be looking at the interior while we swap it out
+        mem::replace(unsafe { &mut *self.val.get() }, val)
+    }
+
+    /// Takes the value out of the cell, leaving Default::default() in its place
+    pub fn take(&self) -> T
+    where
+        T: Default,
+    {
+        self.replace(T::default())
+    }
+
+    /// Swaps the values of two cells. Swapping a cell with itself does nothing.
+    pub fn swap(&self, other: &Cell<T>) {
+        // two distinct Cell<T>s can't partially overlap, so it's either the same
+        // cell or two disjoint ones. Bail out early for the former so we never
+        // create two aliasing &mut T to the same value.
+        if ptr::eq(self, other) {
+            return;
+        }
+        unsafe { ptr::swap(self.val.get(), other.val.get()) }
+    }
+
+    /// Applies `f` to a copy of the contained value and stores the result
+    pub fn update(&self, f: impl FnOnce(T) -> T)
+    where
+        T: Copy,
+    {
+        let old = self.get();
+        self.set(f(old));
+    }
+
+    pub fn into_inner(self) -> T {
+        self.val.into_inner()
+    }
+}
+
+impl<T: ?Sized> Cell<T> {
+    /// Returns a mutable reference to the interior. This is checked at compile
+    /// time since we hold a &mut to the cell itself, so no runtime cost.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.val.get() }
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.val.get()
+    }
+
+    /// Treats a &mut T as a &Cell<T>. The exclusive borrow guarantees no one
+    /// else can observe the value while the cell exists.
+    pub fn from_mut(t: &mut T) -> &Cell<T> {
+        // Cell<T> is repr(transparent) over T
+        unsafe { &*(t as *mut T as *const Cell<T>) }
+    }
+}
+
+impl<T> Cell<[T]> {
+    /// Gives out a slice of cells from a cell of a slice, so that each element
+    /// can be set independently.
+    pub fn as_slice_of_cells(&self) -> &[Cell<T>] {
+        // Cell<[T]> and [Cell<T>] have the same layout, and the length metadata
+        // carries over with the cast.
+        unsafe { &*(self as *const Cell<[T]> as *const [Cell<T>]) }
+    }
+}
+
+// The comparison, Clone and Debug impls all need to look at the value, and the
+// only way a Cell lets you do that is by copying it out, hence T: Copy on all of
+// them. There's no Hash, same as std: a Cell used as a map key could be changed
+// behind the map's back.
+
+impl<T: Copy + fmt::Debug> fmt::Debug for Cell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cell").field("value", &self.get()).finish()
+    }
+}
+
+impl<T: Default> Default for Cell<T> {
+    fn default() -> Self {
+        Cell::new(T::default())
+    }
+}
+
+impl<T: Copy> Clone for Cell<T> {
+    fn clone(&self) -> Self {
+        Cell::new(self.get())
+    }
+}
+
+impl<T> From<T> for Cell<T> {
+    fn from(val: T) -> Self {
+        Cell::new(val)
+    }
+}
+
+impl<T: Copy + PartialEq> PartialEq for Cell<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl<T: Copy + Eq> Eq for Cell<T> {}
+
+impl<T: Copy + PartialOrd> PartialOrd for Cell<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.get().partial_cmp(&other.get())
+    }
+}
+
+impl<T: Copy + Ord> Ord for Cell<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.get().cmp(&other.get())
+    }
 }
 
 // this is a comment
+
+#[cfg(test)]
+mod tests {
+    use super::Cell;
+    #[test]
+    fn mutate_unsafe() {
+        let x = Cell::new(6);
+        x.set(7);
+        assert_eq!(7, x.get());
+    }
+
+    #[test]
+    fn replace_and_take() {
+        let x = Cell::new(String::from("old"));
+        assert_eq!("old", x.replace(String::from("new")));
+        assert_eq!("new", x.take());
+        assert_eq!("", x.into_inner());
+    }
+
+    #[test]
+    fn swap() {
+        let a = Cell::new(vec![1]);
+        let b = Cell::new(vec![2, 3]);
+        a.swap(&b);
+        assert_eq!(vec![2, 3], a.take());
+        assert_eq!(vec![1], b.take());
+
+        // swapping a cell with itself must leave it untouched
+        let c = Cell::new(String::from("self"));
+        c.swap(&c);
+        assert_eq!("self", c.into_inner());
+    }
+
+    #[test]
+    fn get_mut_and_update() {
+        let mut x = Cell::new(1);
+        *x.get_mut() += 1;
+        x.update(|n| n * 10);
+        assert_eq!(20, x.get());
+    }
+
+    #[test]
+    fn slice_of_cells() {
+        let mut arr = [1, 2, 3];
+        let cell: &Cell<[i32]> = Cell::from_mut(&mut arr[..]);
+        let cells = cell.as_slice_of_cells();
+        cells[0].swap(&cells[2]);
+        cells[1].set(20);
+        assert_eq!([3, 20, 1], arr);
+    }
+
+    #[test]
+    fn std_traits() {
+        let a = Cell::new(1);
+        let b = a.clone();
+        b.set(2);
+        assert!(a < b);
+        assert_ne!(a, b);
+        assert_eq!(Cell::from(2), b);
+        assert_eq!(0, Cell::<i32>::default().get());
+        assert_eq!("Cell { value: 1 }", format!("{:?}", a));
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn some_test() {
+        assert!(true);
+    }
+}
@@ -1,8 +1,14 @@
 #![allow(non_snake_case, unused)]
+pub mod alloc;
+pub mod arena;
+pub mod cc;
 pub mod cell;
 pub mod new;
+pub mod oncecell;
 pub mod rc;
 pub mod refcell;
+pub mod sync;
+pub mod tokencell;
 
 #[cfg(test)]
 mod lib_tests {
@@ -13,8 +19,8 @@ mod lib_tests {
         let cell = RefCell::new(42);
         let cell_string = RefCell::new(String::from("hello"));
         let cell_borrow = cell.borrow();
-        assert_eq!(42, *cell_borrow.unwrap());
-        assert_eq!("hello".to_string(), *cell_string.borrow().unwrap());
+        assert_eq!(42, *cell_borrow);
+        assert_eq!("hello".to_string(), *cell_string.borrow());
     }
 
     #[test]
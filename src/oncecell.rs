@@ -0,0 +1,254 @@
+// OnceCell is a cell that can be written to only once. Unlike Cell it hands out
+// plain &T to its contents: once the value is in there it never changes through
+// a &OnceCell, so a &T into it stays valid for as long as the cell is borrowed.
+// Like Cell, it's built on an UnsafeCell and is !Sync.
+//
+// The one dangerous moment is initialization. If the init closure reaches back
+// into the same cell (directly or through a Lazy) and initializes it, the outer
+// call would then overwrite a value someone already holds a &T to. We guard
+// against that with an `initializing` flag: a nested get_or_init() sees the
+// flag and fails with InitError::Reentrant instead of running a second
+// initializer.
+
+use crate::cell::Cell;
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::fmt;
+
+pub struct OnceCell<T> {
+    val: UnsafeCell<Option<T>>,
+    initializing: Cell<bool>,
+}
+
+/// Returned by OnceCell::get_or_try_init() when initialization failed
+#[derive(Debug, PartialEq, Eq)]
+pub enum InitError<E> {
+    /// The init closure tried to initialize the cell it was initializing
+    Reentrant,
+    /// The init closure returned an error
+    Failed(E),
+}
+
+impl<E: fmt::Display> fmt::Display for InitError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::Reentrant => write!(f, "reentrant initialization of OnceCell"),
+            InitError::Failed(err) => write!(f, "OnceCell initialization failed: {}", err),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> Error for InitError<E> {}
+
+// Clears the initializing flag even if the init closure panics, so that the
+// cell can be initialized again afterwards.
+struct InitGuard<'a>(&'a Cell<bool>);
+
+impl Drop for InitGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+impl<T> OnceCell<T> {
+    pub fn new() -> Self {
+        Self {
+            val: UnsafeCell::new(None),
+            initializing: Cell::new(false),
+        }
+    }
+
+    /// Returns the value, or None if the cell hasn't been initialized yet
+    pub fn get(&self) -> Option<&T> {
+        // the only &self write happens while val is None, and we never give out
+        // references to a None, so this can't alias a write
+        unsafe { &*self.val.get() }.as_ref()
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.val.get_mut().as_mut()
+    }
+
+    /// Initializes the cell with `val`. Hands `val` back if the cell is already
+    /// initialized or in the middle of being initialized.
+    pub fn set(&self, val: T) -> Result<(), T> {
+        if self.get().is_some() || self.initializing.get() {
+            return Err(val);
+        }
+        // no &T into the cell exists yet, see get()
+        unsafe { *self.val.get() = Some(val) };
+        Ok(())
+    }
+
+    /// Returns the value, initializing it with `f` first if needed.
+    /// Panics if `f` tries to initialize this same cell.
+    #[track_caller]
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        enum Never {}
+        match self.get_or_try_init(|| Ok::<T, Never>(f())) {
+            Ok(val) => val,
+            Err(InitError::Reentrant) => panic!("reentrant initialization of OnceCell"),
+            Err(InitError::Failed(never)) => match never {},
+        }
+    }
+
+    /// Returns the value, initializing it with `f` first if needed. If `f`
+    /// fails the cell stays uninitialized.
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, InitError<E>>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(val) = self.get() {
+            return Ok(val);
+        }
+        if self.initializing.get() {
+            return Err(InitError::Reentrant);
+        }
+        self.initializing.set(true);
+        let guard = InitGuard(&self.initializing);
+        let val = f().map_err(InitError::Failed)?;
+        drop(guard);
+        // f can't have filled the cell: a nested get_or_init() fails on the
+        // flag, and set() refuses while we're initializing
+        debug_assert!(self.get().is_none());
+        unsafe { *self.val.get() = Some(val) };
+        Ok(self.get().unwrap())
+    }
+
+    /// Takes the value out, leaving the cell uninitialized
+    pub fn take(&mut self) -> Option<T> {
+        self.val.get_mut().take()
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.val.into_inner()
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Lazy is a OnceCell plus the function that initializes it, run the first time
+// the Lazy is dereferenced. The function is kept in a Cell<Option<F>> so that it
+// can be moved out and called through a &Lazy.
+
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    init: Cell<Option<F>>,
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub fn new(init: F) -> Self {
+        Self {
+            cell: OnceCell::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+
+    /// Forces evaluation and returns a reference to the value. Panics if the
+    /// init function panicked on an earlier attempt, or tries to force this
+    /// same Lazy.
+    #[track_caller]
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(f) => f(),
+            None => panic!("Lazy instance has previously been poisoned"),
+        })
+    }
+
+    pub fn into_inner(this: Self) -> Result<T, F> {
+        match this.cell.into_inner() {
+            Some(val) => Ok(val),
+            None => Err(this
+                .init
+                .into_inner()
+                .expect("Lazy instance has previously been poisoned")),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> std::ops::Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        Lazy::force(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InitError, Lazy, OnceCell};
+    use crate::cell::Cell;
+
+    #[test]
+    fn set_once() {
+        let cell = OnceCell::new();
+        assert!(cell.get().is_none());
+        assert_eq!(Ok(()), cell.set(1));
+        assert_eq!(Err(2), cell.set(2));
+        assert_eq!(Some(&1), cell.get());
+        assert_eq!(&1, cell.get_or_init(|| unreachable!()));
+    }
+
+    #[test]
+    fn try_init_failure_leaves_cell_empty() {
+        let cell: OnceCell<String> = OnceCell::new();
+        let err = cell.get_or_try_init(|| Err("nope")).err().unwrap();
+        assert_eq!(InitError::Failed("nope"), err);
+        assert!(cell.get().is_none());
+        let val = cell.get_or_try_init(|| Ok::<_, ()>(String::from("yes")));
+        assert_eq!("yes", val.ok().unwrap());
+    }
+
+    #[test]
+    fn reentrant_init_is_an_error() {
+        let cell: OnceCell<i32> = OnceCell::new();
+        let val = cell.get_or_init(|| {
+            let nested = cell.get_or_try_init(|| Ok::<_, ()>(1));
+            assert_eq!(InitError::Reentrant, nested.err().unwrap());
+            assert_eq!(Err(3), cell.set(3));
+            2
+        });
+        assert_eq!(&2, val);
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant initialization")]
+    fn reentrant_lazy_panics() {
+        thread_local! {
+            static LAZY: Lazy<i32, Box<dyn Fn() -> i32>> =
+                Lazy::new(Box::new(|| LAZY.with(|lazy| **lazy + 1)));
+        }
+        LAZY.with(|lazy| **lazy);
+    }
+
+    #[test]
+    fn take_and_into_inner() {
+        let mut cell = OnceCell::new();
+        cell.set(String::from("once")).unwrap();
+        *cell.get_mut().unwrap() += "!";
+        assert_eq!(Some(String::from("once!")), cell.take());
+        assert!(cell.get().is_none());
+        cell.set(String::from("twice")).unwrap();
+        assert_eq!(Some(String::from("twice")), cell.into_inner());
+    }
+
+    #[test]
+    fn lazy_runs_init_once() {
+        let calls = Cell::new(0);
+        let lazy = Lazy::new(|| {
+            calls.set(calls.get() + 1);
+            vec![1, 2, 3]
+        });
+        assert_eq!(0, calls.get());
+        assert_eq!(3, lazy.len());
+        assert_eq!(6, lazy.iter().sum::<i32>());
+        assert_eq!(1, calls.get());
+        assert_eq!(vec![1, 2, 3], Lazy::into_inner(lazy).ok().unwrap());
+    }
+}
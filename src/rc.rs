@@ -1,79 +1,543 @@
 // Rc enables single-threaded multiple immutable ownership
-// de-allocates the interior only when strong_count -> 0
-// can also have non-ownership references of type Weak<T>
+// drops the interior value when strong_count -> 0
+// can also have non-ownership references of type Weak<T>, and the allocation
+// itself is only freed once weak_count -> 0 as well
 // !Sync+!Send
 // Also note we can't store the ref count in Rc itself because
 // on calling clone, each clone will have it's own ref count so it becomes tricky
 // to de-allocate the Rc
 // also RcInner.val must be cloneable which is why we cannot use
 // just an RcInner<T> inside Rc. We must use a pointer to the data
-// Rc has a *const pointer inside it, which reflects the fact that
+// Rc has a NonNull pointer inside it. Like a *const it reflects the fact that
 // users of Rc shouldn't be able to mutate the interior value
 // however, we need to increment the ref_count, so we use Cell
+// NonNull also tells the compiler the pointer is never null, so Option<Rc<T>>
+// can use null for None and stays the size of a single pointer.
+// Rc also carries a PhantomData<RcInner<T>>. The drop checker doesn't need it:
+// Rc has a Drop impl (without #[may_dangle]), so dropck already assumes that
+// dropping an Rc<T> can drop a T. What the PhantomData does buy is that auto
+// traits and variance follow T the way they would for an owned RcInner<T>, and
+// it says in the type that an Rc<T> owns its RcInner<T>.
 
+// Weak<T> needs the RcInner allocation to stay around after the last Rc is gone
+// (so that upgrade() can look at ref_count and see 0), but the value inside must
+// be dropped as soon as the last Rc goes away. That's why val is a ManuallyDrop:
+// we drop it by hand when ref_count hits 0, and freeing the allocation later
+// won't drop it a second time.
+// weak_count counts the Weak<T>s plus one extra "implicit" weak reference that
+// is collectively owned by all the strong Rc<T>s. This way the allocation is
+// freed exactly when weak_count -> 0, whichever of Rc/Weak happens to be last.
+
+// RcInner<T> also works for unsized T like str, [T] or dyn Trait. An unsized
+// field has to come last, and we make the struct repr(C) so that its layout is
+// exactly "the two counts, then val", which we can compute ourselves from the
+// value's Layout when we have to allocate by hand (see allocate_for_layout()).
+// Rc<dyn Trait> can be built from a Box<dyn Trait>. The direct Rc<T> ->
+// Rc<dyn Trait> coercion needs the unstable CoerceUnsized trait, so that one
+// isn't possible on stable.
+
+// Each Rc and Weak also carries the Allocator its RcInner came from, so the
+// last one out knows where to give the memory back to. For Global that's a
+// zero sized type and costs nothing. Rc::new() and friends use Global, and
+// Rc::new_in() takes any other Allocator, e.g. a &Bump arena.
+
+// With the debug-rc-leaks feature, every RcInner allocated from Global is also
+// recorded in a per thread registry from the moment it's allocated until it's
+// freed, see rc/leaks.rs.
+
+use crate::alloc::{Allocator, Global};
 use crate::cell::Cell;
-struct RcInner<T> {
-    val: T,
+use std::alloc::{self, Layout};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::pin::Pin;
+use std::ptr::{self, NonNull};
+
+#[cfg(feature = "debug-rc-leaks")]
+mod leaks;
+#[cfg(feature = "debug-rc-leaks")]
+pub use leaks::{assert_no_rc_leaks, live_rcs, Checkpoint, LiveRc};
+
+// Counting the references with a plain usize means a program that leaks Rcs
+// on purpose, e.g. mem::forget(rc.clone()) in a loop, could wrap ref_count
+// back around to 0 and free the value while it's still in use. Every increment
+// goes through inc_count() instead, which aborts before the count can wrap.
+// Tests lower the limit so they can actually reach it, and panic instead of
+// aborting so #[should_panic] can observe it.
+#[cfg(not(test))]
+const MAX_REFCOUNT: usize = usize::MAX;
+#[cfg(test)]
+const MAX_REFCOUNT: usize = 16;
+
+fn inc_count(count: &Cell<usize>) {
+    let n = count.get();
+    if n == MAX_REFCOUNT {
+        refcount_overflow();
+    }
+    count.set(n + 1);
+}
+
+#[cold]
+fn refcount_overflow() -> ! {
+    if cfg!(test) {
+        panic!("Rc reference count overflow");
+    }
+    std::process::abort();
+}
+
+// Returns `ptr` pointing at `addr` instead, keeping the length or vtable of a
+// fat pointer. There's no stable API to build a fat pointer from parts, so we
+// overwrite the address half, which is always the first word of a fat pointer
+// (and the only word of a thin one).
+unsafe fn set_data_ptr<T: ?Sized>(mut ptr: *mut T, addr: *mut u8) -> *mut T {
+    ptr::write(&mut ptr as *mut *mut T as *mut *mut u8, addr);
+    ptr
+}
+
+// Offset of val within an RcInner whose value has alignment `align`. The
+// counts come first, then val at the next multiple of its alignment.
+fn data_offset(align: usize) -> usize {
+    let value_layout = Layout::from_size_align(0, align).unwrap();
+    Layout::new::<RcInner<()>>().extend(value_layout).unwrap().1
+}
+
+#[repr(C)]
+struct RcInner<T: ?Sized> {
     ref_count: Cell<usize>,
+    weak_count: Cell<usize>,
+    val: ManuallyDrop<T>,
+}
+
+// Gives up one weak reference to `inner`, freeing the allocation through
+// `alloc` if it was the last one. The value itself must have been dropped
+// (or moved out) already by the last Rc; val is a ManuallyDrop, so freeing
+// the memory doesn't drop it a second time.
+unsafe fn release_weak<T: ?Sized, A: Allocator>(inner: NonNull<RcInner<T>>, alloc: &A) {
+    let ptr = inner.as_ref();
+    match ptr.weak_count.get() {
+        1 => {
+            let layout = Layout::for_value(ptr);
+            #[cfg(feature = "debug-rc-leaks")]
+            if alloc.is_global() {
+                leaks::unregister(inner);
+            }
+            alloc.deallocate(inner.cast(), layout);
+        }
+        n => ptr.weak_count.set(n - 1),
+    }
 }
 
-pub struct Rc<T> {
-    inner: *const RcInner<T>,
+/// A single-threaded reference counted pointer.
+///
+/// Rc<T> owns its T as far as the drop checker is concerned, so it can't
+/// outlive data borrowed by the T it holds. That comes from Rc's Drop impl,
+/// not from the PhantomData; these examples are here as regression guards
+/// against ever weakening it (e.g. with #[may_dangle]):
+///
+/// ```compile_fail,E0597
+/// use UnsafeCells::rc::Rc;
+///
+/// struct PrintOnDrop<'a>(&'a String);
+/// impl Drop for PrintOnDrop<'_> {
+///     fn drop(&mut self) {
+///         println!("{}", self.0);
+///     }
+/// }
+///
+/// let rc;
+/// let s = String::from("dangling");
+/// // s is dropped before rc, so rc's drop would read a dangling &String
+/// rc = Rc::new(PrintOnDrop(&s));
+/// ```
+///
+/// The same goes for a value that is dropped while an Rc still borrows it:
+///
+/// ```compile_fail,E0597
+/// use UnsafeCells::rc::Rc;
+///
+/// let rc;
+/// {
+///     let s = String::from("short-lived");
+///     rc = Rc::new(&s);
+/// }
+/// println!("{}", rc);
+/// ```
+pub struct Rc<T: ?Sized, A: Allocator = Global> {
+    inner: NonNull<RcInner<T>>,
+    phantom: PhantomData<RcInner<T>>,
+    alloc: A,
 }
 
 impl<T> Rc<T> {
     pub fn new(val: T) -> Self {
         // we use Box specifically for a heap allocation
         let inner = Box::new(RcInner {
-            val,
             ref_count: Cell::new(1),
+            weak_count: Cell::new(1),
+            val: ManuallyDrop::new(val),
         });
-        Self {
-            inner: Box::into_raw(inner),
-        }
-        // Using into_raw is essential as if we return Rc { inner: &*Box<T> }, the
+        let inner = NonNull::from(Box::leak(inner));
+        #[cfg(feature = "debug-rc-leaks")]
+        leaks::register(inner);
+        // Box allocates from the global heap, so this is memory that Global can
+        // deallocate later on
+        Self::from_inner(inner, Global)
+        // Using leak is essential as if we return Rc { inner: &*Box<T> }, the
         // Box will be dropped at the end of this scope.
-        // Instead, taking a raw pointer, Box is consumed, but the interior memory
-        // isn't freed. into_raw() returns a *mut T
+        // Instead, by leaking it, Box is consumed, but the interior memory
+        // isn't freed. leak() returns a &mut T which we turn into a NonNull<T>
         // Also you can't just return a Rc { inner: &*Box::new(RcInner<T>) } because
         // again Box will have no owner, and this will be a dangling reference.
     }
 
+    /// Creates an Rc with room for a T that is left uninitialized, so the value
+    /// can be written in place instead of being moved into the Rc.
+    pub fn new_uninit() -> Rc<MaybeUninit<T>> {
+        unsafe {
+            let inner =
+                Rc::<MaybeUninit<T>>::allocate_for_layout(&Global, Layout::new::<T>(), |mem| {
+                    mem as *mut RcInner<MaybeUninit<T>>
+                });
+            Rc::from_inner(NonNull::new_unchecked(inner), Global)
+        }
+    }
+
+    /// Like new_uninit(), but the value's memory is filled with 0 bytes
+    pub fn new_zeroed() -> Rc<MaybeUninit<T>> {
+        let rc = Rc::new_uninit();
+        unsafe {
+            let val = ptr::addr_of_mut!((*rc.inner.as_ptr()).val) as *mut MaybeUninit<T>;
+            val.write(MaybeUninit::zeroed());
+        }
+        rc
+    }
+
+    /// Creates an Rc<[T]> of `len` uninitialized elements
+    pub fn new_uninit_slice(len: usize) -> Rc<[MaybeUninit<T>]> {
+        unsafe { Rc::from_inner(NonNull::new_unchecked(Rc::allocate_for_slice(len)), Global) }
+    }
+
+    /// Creates a Pin<Rc<T>>. The value never moves out of the allocation while
+    /// it's pinned, since getting the Rc back out of the Pin needs T: Unpin.
+    pub fn pin(val: T) -> Pin<Rc<T>> {
+        unsafe { Pin::new_unchecked(Rc::new(val)) }
+    }
+
+    /// Creates a new Rc<T>, giving `data_fn` a Weak<T> to the allocation being
+    /// constructed, so that T can hold (weak) pointers to itself.
+    /// Calling upgrade() on that Weak inside `data_fn` returns None, since the
+    /// value doesn't exist yet.
+    pub fn new_cyclic<F>(data_fn: F) -> Self
+    where
+        F: FnOnce(&Weak<T>) -> T,
+    {
+        // Start out with ref_count 0, so the Weak can't be upgraded, and
+        // weak_count 1 for the Weak we're about to hand out. RcInner is
+        // repr(C) and MaybeUninit<T> is laid out like T, so this is the same
+        // allocation an RcInner<T> would be.
+        let uninit = Box::new(RcInner {
+            ref_count: Cell::new(0),
+            weak_count: Cell::new(1),
+            val: ManuallyDrop::new(MaybeUninit::<T>::uninit()),
+        });
+        let inner = NonNull::from(Box::leak(uninit)).cast::<RcInner<T>>();
+        #[cfg(feature = "debug-rc-leaks")]
+        leaks::register(inner);
+        let weak = Weak {
+            inner,
+            alloc: Global,
+        };
+        // if data_fn panics, dropping weak frees the allocation, and since val
+        // is a ManuallyDrop the uninitialized value is never dropped
+        let val = data_fn(&weak);
+        unsafe {
+            ptr::addr_of_mut!((*inner.as_ptr()).val).write(ManuallyDrop::new(val));
+        }
+        weak.inner().unwrap().ref_count.set(1);
+        // the Weak's share of weak_count becomes the implicit weak reference
+        // owned by the strong pointers
+        mem::forget(weak);
+        Self::from_inner(inner, Global)
+    }
+}
+
+impl<T, A: Allocator> Rc<T, A> {
+    /// Like Rc::new(), but the RcInner is allocated with `alloc`
+    pub fn new_in(val: T, alloc: A) -> Self {
+        unsafe {
+            let inner =
+                Self::allocate_for_layout(&alloc, Layout::new::<T>(), |mem| mem as *mut RcInner<T>);
+            ptr::addr_of_mut!((*inner).val).write(ManuallyDrop::new(val));
+            Self::from_inner(NonNull::new_unchecked(inner), alloc)
+        }
+    }
+
+    /// Clone-on-write: returns a mutable reference to the value, first cloning
+    /// it into a fresh allocation if it is shared. Any Weaks pointing to the old
+    /// allocation are left behind with it.
+    pub fn make_mut(this: &mut Self) -> &mut T
+    where
+        T: Clone,
+        A: Clone,
+    {
+        if !Rc::is_unique(this) {
+            // assigning drops our old Rc, which takes care of the counts
+            *this = Rc::new_in((**this).clone(), this.alloc.clone());
+        }
+        // either it was unique already or we just made a fresh Rc
+        unsafe { &mut this.inner.as_mut().val }
+    }
+
+    /// Returns the inner value if this is the only Rc, otherwise hands the
+    /// Rc back unchanged. Weaks don't count, they just can't be upgraded anymore.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if this.inner().ref_count.get() != 1 {
+            return Err(this);
+        }
+        // don't let Drop run for this, we're doing its job by hand
+        let this = ManuallyDrop::new(this);
+        this.inner().ref_count.set(0);
+        unsafe {
+            // move val and the allocator out instead of dropping them in place
+            let val = ManuallyDrop::take(&mut (*this.inner.as_ptr()).val);
+            let alloc = ptr::read(&this.alloc);
+            release_weak(this.inner, &alloc);
+            Ok(val)
+        }
+    }
+
+    /// Like try_unwrap(), but drops the Rc and returns None if it is shared
+    pub fn into_inner(this: Self) -> Option<T> {
+        Rc::try_unwrap(this).ok()
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Rc<T, A> {
+    fn from_inner(inner: NonNull<RcInner<T>>, alloc: A) -> Self {
+        Self {
+            inner,
+            phantom: PhantomData,
+            alloc,
+        }
+    }
+
+    fn inner(&self) -> &RcInner<T> {
+        // self.inner is safe to deref because we know it will only be deallocated
+        // once ref_count is 0.
+        unsafe { self.inner.as_ref() }
+    }
+
     pub fn strong_count(&self) -> usize {
-        unsafe { &*self.inner }.ref_count.get()
+        self.inner().ref_count.get()
+    }
+
+    /// Number of Weak<T> pointing to this allocation. The implicit weak
+    /// reference held by the strong pointers is not counted.
+    pub fn weak_count(&self) -> usize {
+        self.inner().weak_count.get() - 1
+    }
+
+    /// Creates a new Weak<T> pointer to this allocation
+    pub fn downgrade(this: &Self) -> Weak<T, A>
+    where
+        A: Clone,
+    {
+        let ptr = this.inner();
+        inc_count(&ptr.weak_count);
+        Weak {
+            inner: this.inner,
+            alloc: this.alloc.clone(),
+        }
+    }
+
+    /// The allocator this Rc's memory came from
+    pub fn allocator(this: &Self) -> &A {
+        &this.alloc
+    }
+
+    /// Returns a pointer to the value. It stays valid as long as some Rc to
+    /// this allocation is alive.
+    pub fn as_ptr(this: &Self) -> *const T {
+        // val is a ManuallyDrop<T>, which is repr(transparent) over T
+        unsafe { ptr::addr_of!((*this.inner.as_ptr()).val) as *const T }
+    }
+
+    /// Returns true if both Rcs point to the same allocation
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        ptr::eq(
+            this.inner.as_ptr() as *const u8,
+            other.inner.as_ptr() as *const u8,
+        )
+    }
+
+    // True if this is the only Rc and there are no Weaks. A Weak could otherwise
+    // be upgraded while we're handing out &mut T.
+    fn is_unique(this: &Self) -> bool {
+        let ptr = this.inner();
+        ptr.ref_count.get() == 1 && ptr.weak_count.get() == 1
+    }
+
+    /// Returns a mutable reference to the value if there are no other Rcs
+    /// or Weaks pointing to it.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if Rc::is_unique(this) {
+            // we have &mut to the only pointer into the allocation
+            Some(unsafe { &mut this.inner.as_mut().val })
+        } else {
+            None
+        }
+    }
+
+    // Allocates an RcInner from `alloc` with room for a value of `value_layout`,
+    // with both counts set to 1 and val left uninitialized. `mem_to_inner` turns
+    // the thin pointer to the allocation into a *mut RcInner<T>, i.e. attaches
+    // the length or vtable that an unsized T needs.
+    unsafe fn allocate_for_layout(
+        alloc: &A,
+        value_layout: Layout,
+        mem_to_inner: impl FnOnce(*mut u8) -> *mut RcInner<T>,
+    ) -> *mut RcInner<T> {
+        // same layout as repr(C) gives RcInner<T>, since RcInner<()> is just the
+        // two counts
+        let layout = Layout::new::<RcInner<()>>()
+            .extend(value_layout)
+            .expect("Rc allocation too large")
+            .0
+            .pad_to_align();
+        let mem = match alloc.allocate(layout) {
+            Ok(mem) => mem.as_ptr(),
+            Err(_) => alloc::handle_alloc_error(layout),
+        };
+        let inner = mem_to_inner(mem);
+        ptr::addr_of_mut!((*inner).ref_count).write(Cell::new(1));
+        ptr::addr_of_mut!((*inner).weak_count).write(Cell::new(1));
+        #[cfg(feature = "debug-rc-leaks")]
+        if alloc.is_global() {
+            leaks::register(NonNull::new_unchecked(inner));
+        }
+        inner
+    }
+}
+
+// Raw pointers don't carry an allocator, so these only work with Global
+impl<T: ?Sized> Rc<T> {
+    /// Consumes the Rc, returning a pointer to the value (not to the RcInner),
+    /// e.g. to pass it through C code as user data. The strong count is not
+    /// touched, so the pointer has to be turned back into an Rc with from_raw()
+    /// eventually or the value leaks.
+    pub fn into_raw(this: Self) -> *const T {
+        let ptr = Rc::as_ptr(&this);
+        mem::forget(this);
+        ptr
+    }
+
+    /// Takes back ownership of a pointer returned by into_raw().
+    ///
+    /// # Safety
+    /// `ptr` must come from Rc::<T>::into_raw(), and each into_raw() must be
+    /// matched by at most one from_raw() (or decrement_strong_count()).
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // val sits at a fixed offset behind the counts, walk back to the start
+        // of the RcInner, keeping ptr's metadata for unsized T
+        let offset = data_offset(mem::align_of_val(&*ptr));
+        let inner = set_data_ptr(ptr as *mut RcInner<T>, (ptr as *mut u8).sub(offset));
+        Self::from_inner(NonNull::new_unchecked(inner), Global)
+    }
+
+    /// Increments the strong count of the Rc that `ptr` came from
+    ///
+    /// # Safety
+    /// `ptr` must come from Rc::<T>::into_raw() and its Rc must still be alive.
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        let rc = ManuallyDrop::new(Rc::from_raw(ptr));
+        mem::forget(Rc::clone(&rc));
+    }
+
+    /// Decrements the strong count of the Rc that `ptr` came from, dropping
+    /// the value if that was the last one.
+    ///
+    /// # Safety
+    /// `ptr` must come from Rc::<T>::into_raw(), and the strong count it gives
+    /// up must have been taken by into_raw() or increment_strong_count().
+    pub unsafe fn decrement_strong_count(ptr: *const T) {
+        drop(Rc::from_raw(ptr));
+    }
+}
+
+// Once the MaybeUninit contents have been written, these turn the Rc into one
+// of the initialized type. MaybeUninit<T> has the same layout as T, so it's
+// the same allocation, just with a different type on the pointer.
+
+impl<T> Rc<MaybeUninit<T>> {
+    /// # Safety
+    /// The value must have been initialized.
+    pub unsafe fn assume_init(self) -> Rc<T> {
+        let this = ManuallyDrop::new(self);
+        let inner = this.inner.cast::<RcInner<T>>();
+        #[cfg(feature = "debug-rc-leaks")]
+        leaks::retag(inner);
+        Rc::from_inner(inner, Global)
+    }
+}
+
+impl<T> Rc<[MaybeUninit<T>]> {
+    /// # Safety
+    /// Every element must have been initialized.
+    pub unsafe fn assume_init(self) -> Rc<[T]> {
+        let this = ManuallyDrop::new(self);
+        let inner = NonNull::new_unchecked(this.inner.as_ptr() as *mut RcInner<[T]>);
+        #[cfg(feature = "debug-rc-leaks")]
+        leaks::retag(inner);
+        Rc::from_inner(inner, Global)
+    }
+}
+
+impl<T> Rc<[T]> {
+    // Allocates an Rc<[T]> of `len` elements, leaving them uninitialized
+    unsafe fn allocate_for_slice(len: usize) -> *mut RcInner<[T]> {
+        let value_layout = Layout::array::<T>(len).expect("Rc allocation too large");
+        Self::allocate_for_layout(&Global, value_layout, |mem| {
+            ptr::slice_from_raw_parts_mut(mem as *mut T, len) as *mut RcInner<[T]>
+        })
     }
 }
 
-// Clone returns the exact same struct Rc, which is nothing but the same *const RcInner
+// Clone returns the exact same struct Rc, which is nothing but the same NonNull<RcInner>
 // pointer, which is created with Rc::new() but only after increasing the ref_count.
 // All that is shared between different Rc owners, is the pointer.
 
-impl<T> Clone for Rc<T> {
+impl<T: ?Sized, A: Allocator + Clone> Clone for Rc<T, A> {
     fn clone(&self) -> Self {
-        let ptr = unsafe { &*self.inner };
-        ptr.ref_count.set(ptr.ref_count.get() + 1);
-        Self { inner: self.inner }
+        let ptr = self.inner();
+        inc_count(&ptr.ref_count);
+        Self::from_inner(self.inner, self.alloc.clone())
     }
 }
 
-impl<T> std::ops::Deref for Rc<T> {
+impl<T: ?Sized, A: Allocator> std::ops::Deref for Rc<T, A> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
-        // self.inner is safe to deref because we know it will only be deallocated
-        // once ref_count is 0.
-        &unsafe { &*self.inner }.val
+        &self.inner().val
     }
 }
 
-impl<T> Drop for Rc<T> {
+impl<T: ?Sized, A: Allocator> Drop for Rc<T, A> {
     fn drop(&mut self) {
-        let ptr = unsafe { &*self.inner };
+        let ptr = self.inner();
         match ptr.ref_count.get() {
             1 => unsafe {
-                drop(ptr);
-                let _ = Box::from_raw(self.inner as *mut RcInner<T>);
+                ptr.ref_count.set(0);
                 // we know no one has a shared ptr at this stage so it's fine
-                // to cast it into a *mut pointer.
+                // to get a *mut pointer and drop the value in place.
+                ManuallyDrop::drop(&mut (*self.inner.as_ptr()).val);
+                // give up the implicit weak reference held by the strong pointers,
+                // which frees the allocation if there are no Weak<T> left.
+                release_weak(self.inner, &self.alloc);
             },
             n => {
                 ptr.ref_count.set(n - 1);
@@ -81,3 +545,678 @@ impl<T> Drop for Rc<T> {
         }
     }
 }
+
+// A Weak<T> doesn't keep the value alive, only the RcInner allocation. So a
+// Weak<T> can never be dereferenced directly, it has to be upgraded to an Rc<T>
+// first, which fails once the value has been dropped.
+// Weak::new() doesn't allocate anything, it uses a dangling usize::MAX pointer
+// instead, which can never be upgraded. That can't be the address of a real
+// RcInner: usize::MAX is odd, and an RcInner starts with two usize counts, so
+// it's always aligned to at least 2 bytes (4 on 32 bit targets).
+
+pub struct Weak<T: ?Sized, A: Allocator = Global> {
+    inner: NonNull<RcInner<T>>,
+    alloc: A,
+}
+
+impl<T> Weak<T> {
+    /// Creates a Weak<T> that doesn't point to any allocation.
+    /// Calling upgrade() on it always returns None.
+    pub fn new() -> Self {
+        Self {
+            inner: NonNull::new(usize::MAX as *mut RcInner<T>).expect("usize::MAX is not null"),
+            alloc: Global,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Weak<T, A> {
+    /// Attempts to get an Rc<T> out of this Weak<T>, returning None if the
+    /// value has already been dropped.
+    pub fn upgrade(&self) -> Option<Rc<T, A>>
+    where
+        A: Clone,
+    {
+        let ptr = self.inner()?;
+        match ptr.ref_count.get() {
+            0 => None,
+            _ => {
+                inc_count(&ptr.ref_count);
+                Some(Rc::from_inner(self.inner, self.alloc.clone()))
+            }
+        }
+    }
+
+    /// Number of Rc<T> pointing to this allocation, 0 for Weak::new()
+    pub fn strong_count(&self) -> usize {
+        self.inner().map_or(0, |ptr| ptr.ref_count.get())
+    }
+
+    /// Number of Weak<T> pointing to this allocation, 0 for Weak::new() or
+    /// if there are no strong pointers left.
+    pub fn weak_count(&self) -> usize {
+        match self.inner() {
+            Some(ptr) if ptr.ref_count.get() > 0 => ptr.weak_count.get() - 1,
+            _ => 0,
+        }
+    }
+
+    fn inner(&self) -> Option<&RcInner<T>> {
+        if self.inner.as_ptr() as *mut u8 as usize == usize::MAX {
+            return None;
+        }
+        // the allocation lives as long as weak_count > 0, and we hold one of those
+        Some(unsafe { self.inner.as_ref() })
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Clone> Clone for Weak<T, A> {
+    fn clone(&self) -> Self {
+        if let Some(ptr) = self.inner() {
+            inc_count(&ptr.weak_count);
+        }
+        Self {
+            inner: self.inner,
+            alloc: self.alloc.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Drop for Weak<T, A> {
+    fn drop(&mut self) {
+        if self.inner().is_some() {
+            // the value itself was already dropped by the last Rc<T>
+            unsafe { release_weak(self.inner, &self.alloc) }
+        }
+    }
+}
+
+// Conversions into unsized Rcs. Each of them allocates the RcInner by hand and
+// moves (or copies) the value into it, so the value ends up in the same single
+// allocation as the counts.
+
+impl<T: ?Sized> From<Box<T>> for Rc<T> {
+    fn from(b: Box<T>) -> Self {
+        unsafe {
+            let value_layout = Layout::for_value(&*b);
+            let bptr = Box::into_raw(b);
+            // the RcInner needs the same length/vtable as the Box
+            let inner = Self::allocate_for_layout(&Global, value_layout, |mem| {
+                set_data_ptr(bptr as *mut RcInner<T>, mem)
+            });
+            // move the value over and free the Box's memory without dropping it
+            ptr::copy_nonoverlapping(
+                bptr as *const u8,
+                ptr::addr_of_mut!((*inner).val) as *mut u8,
+                value_layout.size(),
+            );
+            if value_layout.size() != 0 {
+                alloc::dealloc(bptr as *mut u8, value_layout);
+            }
+            Self::from_inner(NonNull::new_unchecked(inner), Global)
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Rc<[T]> {
+    fn from(mut v: Vec<T>) -> Self {
+        unsafe {
+            let inner = Self::allocate_for_slice(v.len());
+            ptr::copy_nonoverlapping(
+                v.as_ptr(),
+                ptr::addr_of_mut!((*inner).val) as *mut T,
+                v.len(),
+            );
+            // the elements have been moved out, so only free the Vec's buffer
+            v.set_len(0);
+            Self::from_inner(NonNull::new_unchecked(inner), Global)
+        }
+    }
+}
+
+impl<T: Clone> From<&[T]> for Rc<[T]> {
+    fn from(v: &[T]) -> Self {
+        Rc::from(v.to_vec())
+    }
+}
+
+impl From<&str> for Rc<str> {
+    fn from(s: &str) -> Self {
+        let rc: Rc<[u8]> = Rc::from(s.as_bytes());
+        // str has the same layout and length metadata as [u8], and the bytes
+        // came from a str, so they are valid UTF-8
+        let rc = ManuallyDrop::new(rc);
+        let inner = unsafe { NonNull::new_unchecked(rc.inner.as_ptr() as *mut RcInner<str>) };
+        #[cfg(feature = "debug-rc-leaks")]
+        leaks::retag(inner);
+        Rc::from_inner(inner, Global)
+    }
+}
+
+impl From<String> for Rc<str> {
+    fn from(s: String) -> Self {
+        Rc::from(&s[..])
+    }
+}
+
+impl<T> FromIterator<T> for Rc<[T]> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Rc::from(iter.into_iter().collect::<Vec<T>>())
+    }
+}
+
+// Standard traits. Rc<T> is meant to stand in for a T, so comparing, hashing
+// and formatting all go straight through to the value. That also makes
+// Rc<str>/Rc<T> usable as map keys, with Borrow letting you look them up by
+// &str/&T. Two Rcs are equal if their values are, not only if they share an
+// allocation; use Rc::ptr_eq() for that.
+
+impl<T: ?Sized + fmt::Debug, A: Allocator> fmt::Debug for Rc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, A: Allocator> fmt::Display for Rc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> fmt::Pointer for Rc<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&Rc::as_ptr(self), f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> fmt::Debug for Weak<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(Weak)")
+    }
+}
+
+impl<T: Default> Default for Rc<T> {
+    fn default() -> Self {
+        Rc::new(T::default())
+    }
+}
+
+impl<T> From<T> for Rc<T> {
+    fn from(val: T) -> Self {
+        Rc::new(val)
+    }
+}
+
+impl<T: ?Sized + PartialEq, A: Allocator> PartialEq for Rc<T, A> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq, A: Allocator> Eq for Rc<T, A> {}
+
+impl<T: ?Sized + PartialOrd, A: Allocator> PartialOrd for Rc<T, A> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord, A: Allocator> Ord for Rc<T, A> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash, A: Allocator> Hash for Rc<T, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Borrow<T> for Rc<T, A> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized, A: Allocator> AsRef<T> for Rc<T, A> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rc, Weak};
+    use crate::cell::Cell;
+
+    // bumps a counter when dropped so we can tell exactly when val goes away
+    struct DropCounter<'a>(&'a Cell<usize>);
+    impl Drop for DropCounter<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn weak_upgrade() {
+        let rc = Rc::new(String::from("parent"));
+        let weak = Rc::downgrade(&rc);
+        assert_eq!(1, Rc::weak_count(&rc));
+        assert_eq!(1, weak.strong_count());
+
+        let upgraded = weak.upgrade().unwrap();
+        assert_eq!("parent", *upgraded);
+        assert_eq!(2, Rc::strong_count(&rc));
+        drop(upgraded);
+        drop(rc);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(0, weak.strong_count());
+        assert_eq!(0, weak.weak_count());
+    }
+
+    #[test]
+    fn val_dropped_before_allocation() {
+        let drops = Cell::new(0);
+        let rc = Rc::new(DropCounter(&drops));
+        let weak = Rc::downgrade(&rc);
+        let weak2 = weak.clone();
+        assert_eq!(2, Rc::weak_count(&rc));
+        drop(rc);
+        // value is gone even though the Weaks still keep the allocation around
+        assert_eq!(1, drops.get());
+        drop(weak);
+        drop(weak2);
+        assert_eq!(1, drops.get());
+    }
+
+    #[test]
+    fn get_mut() {
+        let mut rc = Rc::new(1);
+        *Rc::get_mut(&mut rc).unwrap() += 1;
+        let weak = Rc::downgrade(&rc);
+        assert!(Rc::get_mut(&mut rc).is_none());
+        drop(weak);
+        let other = rc.clone();
+        assert!(Rc::get_mut(&mut rc).is_none());
+        drop(other);
+        assert_eq!(Some(&mut 2), Rc::get_mut(&mut rc));
+    }
+
+    #[test]
+    fn make_mut_clones_on_write() {
+        let mut a = Rc::new(vec![1]);
+        let b = a.clone();
+        let weak = Rc::downgrade(&a);
+        Rc::make_mut(&mut a).push(2);
+        assert_eq!(vec![1, 2], *a);
+        assert_eq!(vec![1], *b);
+        // the Weak stays with the original allocation
+        assert_eq!(vec![1], *weak.upgrade().unwrap());
+
+        // unique, so no clone this time
+        let mut c = Rc::new(String::from("solo"));
+        let before = &*c as *const String;
+        Rc::make_mut(&mut c).push('!');
+        assert_eq!(before, &*c as *const String);
+    }
+
+    #[test]
+    fn try_unwrap_and_into_inner() {
+        let drops = Cell::new(0);
+        let a = Rc::new(DropCounter(&drops));
+        let b = a.clone();
+        let weak = Rc::downgrade(&a);
+        let a = Rc::try_unwrap(a).err().unwrap();
+        assert!(Rc::into_inner(b).is_none());
+        let val = Rc::try_unwrap(a).ok().unwrap();
+        assert!(weak.upgrade().is_none());
+        assert_eq!(0, drops.get());
+        drop(val);
+        assert_eq!(1, drops.get());
+    }
+
+    // MAX_REFCOUNT is lowered to 16 under cfg(test)
+    #[test]
+    #[should_panic(expected = "Rc reference count overflow")]
+    fn clone_overflow() {
+        let rc = Rc::new(());
+        loop {
+            std::mem::forget(rc.clone());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Rc reference count overflow")]
+    fn downgrade_overflow() {
+        let rc = Rc::new(());
+        loop {
+            std::mem::forget(Rc::downgrade(&rc));
+        }
+    }
+
+    #[test]
+    fn upgrade_overflow_leaves_count_intact() {
+        let rc = Rc::new(());
+        let weak = Rc::downgrade(&rc);
+        for _ in 1..16 {
+            std::mem::forget(weak.upgrade().unwrap());
+        }
+        assert_eq!(16, Rc::strong_count(&rc));
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| weak.upgrade()));
+        assert!(res.is_err());
+        // no wrap-around, the count is pinned at the limit
+        assert_eq!(16, Rc::strong_count(&rc));
+    }
+
+    #[test]
+    fn option_rc_is_pointer_sized() {
+        use std::mem::size_of;
+        assert_eq!(size_of::<usize>(), size_of::<Option<Rc<String>>>());
+        assert_eq!(size_of::<usize>(), size_of::<Option<Weak<String>>>());
+    }
+
+    #[test]
+    fn unsized_str_and_slices() {
+        let s: Rc<str> = Rc::from("hello");
+        let s2 = s.clone();
+        assert_eq!("hello", &*s2);
+        assert_eq!(2, Rc::strong_count(&s));
+
+        let drops = Cell::new(0);
+        let slice: Rc<[DropCounter]> = (0..3).map(|_| DropCounter(&drops)).collect();
+        assert_eq!(3, slice.len());
+        let weak = Rc::downgrade(&slice);
+        drop(slice);
+        assert_eq!(3, drops.get());
+        assert!(weak.upgrade().is_none());
+
+        let empty: Rc<[u64]> = Rc::from(Vec::new());
+        assert!(empty.is_empty());
+        let zsts: Rc<[()]> = Rc::from(vec![(), ()]);
+        assert_eq!(2, zsts.len());
+    }
+
+    #[test]
+    fn dyn_trait_from_box() {
+        use std::fmt::Display;
+        let b: Box<dyn Display> = Box::new(String::from("boxed"));
+        let rc: Rc<dyn Display> = Rc::from(b);
+        let rc2 = rc.clone();
+        assert_eq!("boxed", rc2.to_string());
+
+        trait Counted {
+            fn count(&self) -> usize;
+        }
+        impl Counted for DropCounter<'_> {
+            fn count(&self) -> usize {
+                self.0.get()
+            }
+        }
+        let drops = Cell::new(0);
+        let b: Box<dyn Counted> = Box::new(DropCounter(&drops));
+        let mut rc: Rc<dyn Counted> = Rc::from(b);
+        assert!(Rc::get_mut(&mut rc).is_some());
+        assert_eq!(0, rc.count());
+        drop(rc);
+        assert_eq!(1, drops.get());
+    }
+
+    #[test]
+    fn new_cyclic_self_reference() {
+        struct Gadget {
+            me: Weak<Gadget>,
+            name: &'static str,
+        }
+        let gadget = Rc::new_cyclic(|me| {
+            // nothing to upgrade to yet
+            assert!(me.upgrade().is_none());
+            Gadget {
+                me: me.clone(),
+                name: "gadget",
+            }
+        });
+        assert_eq!("gadget", gadget.me.upgrade().unwrap().name);
+        assert_eq!(1, Rc::strong_count(&gadget));
+        assert_eq!(1, Rc::weak_count(&gadget));
+    }
+
+    #[test]
+    fn new_cyclic_parent_child_tree() {
+        struct Node {
+            value: i32,
+            parent: Weak<Node>,
+            children: Vec<Rc<Node>>,
+        }
+        let leaf = |parent: &Weak<Node>, value| {
+            Rc::new(Node {
+                value,
+                parent: parent.clone(),
+                children: Vec::new(),
+            })
+        };
+        let root = Rc::new_cyclic(|root| Node {
+            value: 0,
+            parent: Weak::new(),
+            children: vec![
+                leaf(root, 1),
+                Rc::new_cyclic(|mid| Node {
+                    value: 2,
+                    parent: root.clone(),
+                    children: vec![leaf(mid, 3), leaf(mid, 4)],
+                }),
+            ],
+        });
+        let mid = &root.children[1];
+        assert!(root.parent.upgrade().is_none());
+        assert_eq!(0, mid.parent.upgrade().unwrap().value);
+        for child in &mid.children {
+            assert!(Rc::ptr_eq(mid, &child.parent.upgrade().unwrap()));
+        }
+        // two children + the grandchildren's parent pointers, no cycles of Rcs
+        assert_eq!(2, Rc::weak_count(&root));
+        assert_eq!(2, Rc::weak_count(mid));
+        let weak_mid = Rc::downgrade(mid);
+        let weak_leaf = Rc::downgrade(&mid.children[0]);
+        // the parent pointers are weak, so dropping the root frees everything
+        drop(root);
+        assert!(weak_mid.upgrade().is_none());
+        assert!(weak_leaf.upgrade().is_none());
+    }
+
+    #[test]
+    fn new_cyclic_panic_frees_allocation() {
+        let res = std::panic::catch_unwind(|| {
+            Rc::<String>::new_cyclic(|_| panic!("construction failed"));
+        });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn new_uninit_and_zeroed() {
+        let mut rc = Rc::<[u8; 4096]>::new_uninit();
+        Rc::get_mut(&mut rc).unwrap().write([7; 4096]);
+        let rc = unsafe { rc.assume_init() };
+        assert!(rc.iter().all(|&b| b == 7));
+
+        let zeroed = unsafe { Rc::<(u32, u64)>::new_zeroed().assume_init() };
+        assert_eq!((0, 0), *zeroed);
+
+        let drops = Cell::new(0);
+        let mut slice = Rc::<DropCounter>::new_uninit_slice(3);
+        for elem in Rc::get_mut(&mut slice).unwrap() {
+            elem.write(DropCounter(&drops));
+        }
+        let slice = unsafe { slice.assume_init() };
+        assert_eq!(3, slice.len());
+        drop(slice);
+        assert_eq!(3, drops.get());
+    }
+
+    #[test]
+    fn pin_keeps_address() {
+        use std::marker::PhantomPinned;
+        struct SelfAddr {
+            addr: Cell<usize>,
+            _pinned: PhantomPinned,
+        }
+        let pinned = Rc::pin(SelfAddr {
+            addr: Cell::new(0),
+            _pinned: PhantomPinned,
+        });
+        pinned.addr.set(&*pinned as *const SelfAddr as usize);
+        let clone = pinned.clone();
+        assert_eq!(clone.addr.get(), &*clone as *const SelfAddr as usize);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let rc = Rc::new(String::from("user data"));
+        let weak = Rc::downgrade(&rc);
+        assert_eq!(Rc::as_ptr(&rc), &*rc as *const String);
+
+        let ptr = Rc::into_raw(rc);
+        assert_eq!(1, weak.strong_count());
+        assert_eq!("user data", unsafe { &*ptr });
+
+        unsafe { Rc::increment_strong_count(ptr) };
+        assert_eq!(2, weak.strong_count());
+        unsafe { Rc::decrement_strong_count(ptr) };
+        assert_eq!(1, weak.strong_count());
+
+        let rc = unsafe { Rc::from_raw(ptr) };
+        assert_eq!(1, Rc::strong_count(&rc));
+        assert_eq!(1, Rc::weak_count(&rc));
+        drop(rc);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn raw_through_c_callback() {
+        use std::ffi::c_void;
+        extern "C" fn callback(user_data: *mut c_void) {
+            // borrow the Rc for the duration of the callback without taking it over
+            let rc =
+                std::mem::ManuallyDrop::new(unsafe { Rc::from_raw(user_data as *const Cell<u32>) });
+            rc.set(rc.get() + 1);
+        }
+        let counter = Rc::new(Cell::new(0u32));
+        let user_data = Rc::into_raw(counter.clone()) as *mut c_void;
+        assert_eq!(2, Rc::strong_count(&counter));
+        callback(user_data);
+        callback(user_data);
+        assert_eq!(2, Rc::strong_count(&counter));
+        unsafe { Rc::decrement_strong_count(user_data as *const Cell<u32>) };
+        assert_eq!(1, Rc::strong_count(&counter));
+        assert_eq!(2, counter.get());
+    }
+
+    #[test]
+    fn raw_unsized_and_overaligned() {
+        #[repr(align(64))]
+        struct Aligned(u8);
+        let rc = Rc::new(Aligned(3));
+        let ptr = Rc::into_raw(rc);
+        assert_eq!(0, ptr as usize % 64);
+        let rc = unsafe { Rc::from_raw(ptr) };
+        assert_eq!(3, rc.0);
+
+        let s: Rc<str> = Rc::from("unsized");
+        let ptr = Rc::into_raw(s);
+        unsafe { Rc::increment_strong_count(ptr) };
+        let s = unsafe { Rc::from_raw(ptr) };
+        assert_eq!(2, Rc::strong_count(&s));
+        assert_eq!("unsized", &*s);
+        unsafe { Rc::decrement_strong_count(ptr) };
+        assert_eq!(1, Rc::strong_count(&s));
+    }
+
+    #[test]
+    fn new_in_bump_arena() {
+        use crate::alloc::Bump;
+        enum Expr<'a> {
+            Num(i64),
+            Add(Rc<Expr<'a>, &'a Bump>, Rc<Expr<'a>, &'a Bump>),
+            Mul(Rc<Expr<'a>, &'a Bump>, Rc<Expr<'a>, &'a Bump>),
+        }
+        fn eval(e: &Expr<'_>) -> i64 {
+            match e {
+                Expr::Num(n) => *n,
+                Expr::Add(a, b) => eval(a) + eval(b),
+                Expr::Mul(a, b) => eval(a) * eval(b),
+            }
+        }
+
+        let bump = Bump::new();
+        // (2 + 3) * (2 + 3), with the sum shared between both sides
+        let two = Rc::new_in(Expr::Num(2), &bump);
+        let three = Rc::new_in(Expr::Num(3), &bump);
+        let sum = Rc::new_in(Expr::Add(two, three), &bump);
+        let product = Rc::new_in(Expr::Mul(sum.clone(), sum.clone()), &bump);
+        assert_eq!(25, eval(&product));
+        assert_eq!(3, Rc::strong_count(&sum));
+        assert_eq!(1, bump.chunk_count());
+        assert!(std::ptr::eq(&bump, *Rc::allocator(&product)));
+    }
+
+    #[test]
+    fn new_in_drops_values_and_weaks() {
+        use crate::alloc::Bump;
+        let bump = Bump::new();
+        let drops = Cell::new(0);
+        let mut a = Rc::new_in(DropCounter(&drops), &bump);
+        assert!(Rc::get_mut(&mut a).is_some());
+        let weak = Rc::downgrade(&a);
+        let b = a.clone();
+        drop(a);
+        assert!(weak.upgrade().is_some());
+        drop(b);
+        assert_eq!(1, drops.get());
+        assert!(weak.upgrade().is_none());
+
+        let mut v = Rc::new_in(vec![1], &bump);
+        let shared = v.clone();
+        Rc::make_mut(&mut v).push(2);
+        assert_eq!(vec![1], *shared);
+        assert_eq!(vec![1, 2], Rc::try_unwrap(v).ok().unwrap());
+    }
+
+    #[test]
+    fn std_traits() {
+        use std::collections::{BTreeSet, HashMap};
+        let a: Rc<str> = Rc::from("a");
+        let b = Rc::from("b");
+        assert!(a < b);
+        assert_eq!(a, Rc::from("a"));
+        assert_eq!("\"a\"", format!("{:?}", a));
+        assert_eq!("a", format!("{}", a));
+        assert_eq!(format!("{:p}", Rc::as_ptr(&a)), format!("{:p}", a));
+
+        let mut map = HashMap::new();
+        map.insert(a.clone(), 1);
+        map.insert(b, 2);
+        // Borrow<str> lets us look up Rc<str> keys with a &str
+        assert_eq!(Some(&1), map.get("a"));
+        let set: BTreeSet<Rc<i32>> = [3, 1, 2].iter().map(|&n| Rc::from(n)).collect();
+        assert_eq!(vec![1, 2, 3], set.iter().map(|n| **n).collect::<Vec<_>>());
+
+        assert_eq!(0, *Rc::<i32>::default());
+        let s: &str = a.as_ref();
+        assert_eq!("a", s);
+        assert_eq!("(Weak)", format!("{:?}", Rc::downgrade(&a)));
+    }
+
+    #[test]
+    fn weak_new() {
+        let weak: Weak<i32> = Weak::new();
+        assert!(weak.upgrade().is_none());
+        assert_eq!(0, weak.strong_count());
+        let _clone = weak.clone();
+    }
+}
@@ -0,0 +1,293 @@
+// A registry of every RcInner that is currently allocated on this thread, so
+// that tests can find Rc graphs that were never freed, typically because of
+// a cycle of strong references that was never broken.
+// Rc is !Send, so an RcInner is allocated, used and freed on one thread, and a
+// thread_local registry needs no locking. Entries are added when the RcInner
+// is allocated and removed when the allocation is freed (not when the value
+// is dropped), so an allocation kept alive only by Weaks still shows up, with
+// a strong count of 0.
+// Only allocations from Global are registered. Other allocators can free an
+// RcInner's memory without Rc ever hearing about it: dropping a Bump frees
+// every Rc in it, including leaked ones, and the registry would be left
+// reading freed memory.
+// Allocations can be reused at the same address, so every registration also
+// gets a serial number, and a Checkpoint remembers serial numbers rather than
+// addresses.
+
+use super::RcInner;
+use crate::refcell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::ptr::NonNull;
+
+struct Entry {
+    serial: u64,
+    type_name: &'static str,
+    // RcInner is repr(C), so every RcInner<T> starts with the counts of an
+    // RcInner<()>, which is all the registry ever reads
+    counts: NonNull<RcInner<()>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_serial: u64,
+    live: HashMap<usize, Entry>,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+// Rcs can be dropped by other thread locals' destructors after REGISTRY itself
+// is gone, so all the accesses go through try_with and just give up then.
+fn with_registry<R>(f: impl FnOnce(&mut Registry) -> R) -> Option<R> {
+    REGISTRY
+        .try_with(|registry| f(&mut registry.borrow_mut()))
+        .ok()
+}
+
+pub(super) fn register<T: ?Sized>(inner: NonNull<RcInner<T>>) {
+    with_registry(|registry| {
+        let serial = registry.next_serial;
+        registry.next_serial += 1;
+        registry.live.insert(
+            inner.cast::<u8>().as_ptr() as usize,
+            Entry {
+                serial,
+                type_name: std::any::type_name::<T>(),
+                counts: inner.cast(),
+            },
+        );
+    });
+}
+
+pub(super) fn unregister<T: ?Sized>(inner: NonNull<RcInner<T>>) {
+    with_registry(|registry| {
+        registry
+            .live
+            .remove(&(inner.cast::<u8>().as_ptr() as usize))
+    });
+}
+
+// Records the allocation's new type, for the places that reuse an allocation
+// as another type, e.g. Rc<[u8]> -> Rc<str> or Rc<MaybeUninit<T>> -> Rc<T>
+pub(super) fn retag<T: ?Sized>(inner: NonNull<RcInner<T>>) {
+    with_registry(|registry| {
+        if let Some(entry) = registry
+            .live
+            .get_mut(&(inner.cast::<u8>().as_ptr() as usize))
+        {
+            entry.type_name = std::any::type_name::<T>();
+        }
+    });
+}
+
+/// An Rc allocation that is still alive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveRc {
+    pub type_name: &'static str,
+    pub strong_count: usize,
+    /// Weak count, not including the implicit weak reference held by the Rcs
+    pub weak_count: usize,
+}
+
+impl fmt::Display for LiveRc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Rc<{}> (strong: {}, weak: {})",
+            self.type_name, self.strong_count, self.weak_count
+        )
+    }
+}
+
+// Only entries that pass `keep` are reported, ordered by when they were
+// allocated
+fn live_where(keep: impl Fn(u64) -> bool) -> Vec<LiveRc> {
+    with_registry(|registry| {
+        let mut entries: Vec<_> = registry
+            .live
+            .values()
+            .filter(|entry| keep(entry.serial))
+            .collect();
+        entries.sort_by_key(|entry| entry.serial);
+        entries
+            .into_iter()
+            .map(|entry| {
+                // the allocation is still registered, so it hasn't been freed
+                let inner = unsafe { entry.counts.as_ref() };
+                let strong_count = inner.ref_count.get();
+                let weak_count = inner.weak_count.get() - (strong_count > 0) as usize;
+                LiveRc {
+                    type_name: entry.type_name,
+                    strong_count,
+                    weak_count,
+                }
+            })
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Returns every Rc allocation that is alive on this thread right now
+pub fn live_rcs() -> Vec<LiveRc> {
+    live_where(|_| true)
+}
+
+/// Remembers which Rc allocations were alive when it was taken, so that
+/// leaks() can report the ones allocated since that are still alive.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    next_serial: u64,
+}
+
+impl Checkpoint {
+    pub fn new() -> Self {
+        let next_serial = with_registry(|registry| registry.next_serial).unwrap_or(0);
+        Checkpoint { next_serial }
+    }
+
+    /// Returns the Rc allocations made on this thread since the checkpoint
+    /// was taken that haven't been freed yet
+    pub fn leaks(&self) -> Vec<LiveRc> {
+        live_where(|serial| serial >= self.next_serial)
+    }
+}
+
+impl Default for Checkpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `f` and panics if any Rc allocated while it ran is still alive once it
+/// returns, listing the leaked allocations. `f`'s return value is dropped
+/// before the check, so it can't be the thing that keeps an Rc alive.
+#[track_caller]
+pub fn assert_no_rc_leaks<R>(f: impl FnOnce() -> R) {
+    let checkpoint = Checkpoint::new();
+    drop(f());
+    let leaks = checkpoint.leaks();
+    if !leaks.is_empty() {
+        let leaks: Vec<_> = leaks.iter().map(ToString::to_string).collect();
+        panic!(
+            "{} Rc allocation(s) leaked: {}",
+            leaks.len(),
+            leaks.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::Bump;
+    use crate::rc::{Rc, Weak};
+
+    struct Node {
+        next: RefCell<Option<Rc<Node>>>,
+    }
+
+    #[test]
+    fn freed_rcs_are_not_reported() {
+        assert_no_rc_leaks(|| {
+            let a = Rc::new(1);
+            let b = a.clone();
+            let w = Rc::downgrade(&a);
+            let s: Rc<str> = Rc::from("hello");
+            (b, w, s)
+        });
+    }
+
+    #[test]
+    fn cycle_is_reported_with_counts() {
+        let checkpoint = Checkpoint::new();
+        let a = Rc::new(Node {
+            next: RefCell::new(None),
+        });
+        let b = Rc::new(Node {
+            next: RefCell::new(Some(a.clone())),
+        });
+        *a.next.borrow_mut() = Some(b.clone());
+        drop((a, b));
+
+        let leaks = checkpoint.leaks();
+        assert_eq!(leaks.len(), 2);
+        for leak in &leaks {
+            assert!(leak.type_name.ends_with("Node"));
+            assert_eq!(leak.strong_count, 1);
+            assert_eq!(leak.weak_count, 0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "1 Rc allocation(s) leaked: Rc<i32> (strong: 1, weak: 0)")]
+    fn assert_no_rc_leaks_panics_on_leak() {
+        assert_no_rc_leaks(|| std::mem::forget(Rc::new(5)));
+    }
+
+    #[test]
+    fn weak_only_allocation_has_zero_strong_count() {
+        let checkpoint = Checkpoint::new();
+        let w = Rc::downgrade(&Rc::new(String::new()));
+        let leaks = checkpoint.leaks();
+        assert_eq!(leaks.len(), 1);
+        assert_eq!(leaks[0].strong_count, 0);
+        assert_eq!(leaks[0].weak_count, 1);
+        drop(w);
+        assert!(checkpoint.leaks().is_empty());
+    }
+
+    #[test]
+    fn checkpoint_ignores_older_allocations() {
+        let old = Rc::new(0u8);
+        let checkpoint = Checkpoint::new();
+        assert!(checkpoint.leaks().is_empty());
+        assert!(live_rcs().iter().any(|rc| rc.type_name == "u8"));
+        let _new: Weak<u8> = Weak::new();
+        assert!(checkpoint.leaks().is_empty());
+        drop(old);
+    }
+
+    #[test]
+    fn other_allocators_are_not_tracked() {
+        struct BumpNode<'a> {
+            next: RefCell<Option<Rc<BumpNode<'a>, &'a Bump>>>,
+        }
+        let checkpoint = Checkpoint::new();
+        let bump = Bump::new();
+        let node = Rc::new_in(
+            BumpNode {
+                next: RefCell::new(None),
+            },
+            &bump,
+        );
+        *node.next.borrow_mut() = Some(node.clone());
+        drop(node);
+        assert!(checkpoint.leaks().is_empty());
+        // frees the leaked cycle's memory, which must not be read afterwards
+        drop(bump);
+        assert!(checkpoint.leaks().is_empty());
+        // make_mut() goes through new_in(), but with Global it's still tracked
+        let mut a = Rc::new(1);
+        let b = a.clone();
+        *Rc::make_mut(&mut a) += 1;
+        assert_eq!(checkpoint.leaks().len(), 2);
+        drop((a, b));
+    }
+
+    #[test]
+    fn reused_allocations_report_their_final_type() {
+        let checkpoint = Checkpoint::new();
+        let s: Rc<str> = Rc::from("hello");
+        let n = unsafe { Rc::<u32>::new_zeroed().assume_init() };
+        let mut slice = Rc::<u16>::new_uninit_slice(2);
+        Rc::get_mut(&mut slice).unwrap()[0].write(1);
+        Rc::get_mut(&mut slice).unwrap()[1].write(2);
+        let slice = unsafe { slice.assume_init() };
+
+        let names: Vec<_> = checkpoint.leaks().iter().map(|rc| rc.type_name).collect();
+        assert_eq!(names, ["str", "u32", "[u16]"]);
+        drop((s, n, slice));
+    }
+}
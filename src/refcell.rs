@@ -1,5 +1,12 @@
 use crate::cell::Cell;
 use std::cell::UnsafeCell;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::panic::Location;
+use std::ptr::NonNull;
 // RefCell is a RAII guard pattern
 // RAII stands for resource acquisiton is initiation
 // which means that objects are tied to resources
@@ -12,16 +19,26 @@ use std::cell::UnsafeCell;
 
 pub struct RefCell<T> {
     val: UnsafeCell<T>,
-    state: Cell<RefState>,
+    flag: BorrowFlag,
 }
 // We only need Copy on RefState because we'll be wrapping them
 // in a Cell for interior mutability but since Clone
 // is a super-trait for Copy, we derive both of them.
-#[derive(Copy, Clone)]
-enum RefState {
+// Exclusive counts the RefMuts sharing the one exclusive borrow. That is always
+// 1 unless RefMut::map_split() has carved the value up into disjoint parts.
+// Shared(n) is bounded by MAX_SHARED so that forgetting Refs in a loop can't wrap
+// the count around to 0 and let a RefMut alias them. Once the limit is reached
+// try_borrow() fails with a BorrowError instead. Tests lower the limit to reach it.
+#[cfg(not(test))]
+const MAX_SHARED: usize = usize::MAX;
+#[cfg(test)]
+const MAX_SHARED: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RefState {
     None,
     Shared(usize),
-    Exclusive,
+    Exclusive(usize),
 }
 
 impl<T> RefCell<T> {
@@ -30,46 +47,195 @@ impl<T> RefCell<T> {
     /// # Example:
     ///
     /// ```
+    /// use UnsafeCells::refcell::RefCell;
     ///
     /// let cell = RefCell::new(42);
     /// let cell_string = RefCell::new(String::from("hello"));
     /// let cell_borrow = cell.borrow();
-    /// assert_eq!(42, *cell_borrow.unwrap());
-    /// assert_eq!("hello".to_string(), *cell_string.borrow().unwrap());
+    /// assert_eq!(42, *cell_borrow);
+    /// assert_eq!("hello".to_string(), *cell_string.borrow());
     /// ```
     pub fn new(val: T) -> Self {
         Self {
             val: UnsafeCell::new(val),
-            state: Cell::new(RefState::None),
+            flag: BorrowFlag::new(),
         }
     }
 
-    pub fn borrow(&self) -> Option<Ref<'_, T>> {
-        match self.state.get() {
-            RefState::None => {
-                self.state.set(RefState::Shared(1));
-                Some(Ref { reference: self })
-            }
-            RefState::Shared(n) => {
-                self.state.set(RefState::Shared(n + 1));
-                Some(Ref { reference: self })
-            }
-            RefState::Exclusive => None,
+    /// Immutably borrows the value, panicking if it is currently mutably borrowed.
+    /// Use try_borrow() for a non-panicking variant.
+    #[track_caller]
+    pub fn borrow(&self) -> Ref<'_, T> {
+        match self.try_borrow() {
+            Ok(r) => r,
+            Err(err) => panic!("{}", err),
         }
     }
-    pub fn borrow_mut(&self) -> Option<RefMut<'_, T>> {
-        match self.state.get() {
-            RefState::None => {
-                self.state.set(RefState::Exclusive);
-                Some(RefMut { reference: self })
-            }
-            RefState::Shared(_) => None,
-            RefState::Exclusive => None,
+
+    /// Mutably borrows the value, panicking if it is currently borrowed at all.
+    /// Use try_borrow_mut() for a non-panicking variant.
+    #[track_caller]
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        match self.try_borrow_mut() {
+            Ok(r) => r,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Consumes the RefCell, returning the value. Taking self by value means
+    /// there can't be any borrows left.
+    pub fn into_inner(self) -> T {
+        self.val.into_inner()
+    }
+
+    #[track_caller]
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+        let borrow = BorrowRef::new(&self.flag)?;
+        Ok(Ref {
+            // UnsafeCell::get() never returns null
+            value: unsafe { NonNull::new_unchecked(self.val.get()) },
+            borrow,
+        })
+    }
+
+    #[track_caller]
+    pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, BorrowMutError> {
+        let borrow = BorrowRefMut::new(&self.flag)?;
+        Ok(RefMut {
+            value: unsafe { NonNull::new_unchecked(self.val.get()) },
+            borrow,
+            marker: PhantomData,
+        })
+    }
+}
+
+/// Mutably borrows every cell in `cells` at once, e.g. two elements of a
+/// Vec<RefCell<T>>. Fails if any of them is already borrowed, or if the same
+/// cell is passed more than once, in which case none of them stay borrowed.
+/// BorrowMutError::is_duplicate() tells the two failures apart.
+#[track_caller]
+pub fn borrow_mut_many<'a, T, const N: usize>(
+    cells: [&'a RefCell<T>; N],
+) -> Result<[RefMut<'a, T>; N], BorrowMutError> {
+    // A cell that comes up twice would block its own second borrow. Catch it
+    // up front so the error doesn't depend on which order they're borrowed in.
+    for (i, cell) in cells.iter().enumerate() {
+        if cells[..i].iter().any(|other| std::ptr::eq(*other, *cell)) {
+            // nothing is actually borrowed, so there's no state or location
+            // to report
+            return Err(BorrowMutError {
+                state: RefState::None,
+                borrowed_at: Vec::new(),
+                duplicate: true,
+            });
+        }
+    }
+    // If one of the borrows fails, `?` drops the RefMuts we already have,
+    // which releases their borrows again.
+    let mut borrows = Vec::with_capacity(N);
+    for cell in cells.iter() {
+        borrows.push(cell.try_borrow_mut()?);
+    }
+    match borrows.try_into() {
+        Ok(borrows) => Ok(borrows),
+        Err(_) => unreachable!("borrowed {} cells", N),
+    }
+}
+
+// The errors carry the RefState that was in the way so that the message can
+// tell a shared borrow apart from an exclusive one. A BorrowError is caused by
+// an exclusive borrow, or by the shared count hitting MAX_SHARED. A
+// BorrowMutError can be caused by either kind of borrow.
+// With the debug-borrows feature they also list where the outstanding borrows
+// were made. Without it, borrowed_at is always empty.
+
+/// Returned by RefCell::try_borrow() when the value is mutably borrowed
+#[derive(Debug)]
+pub struct BorrowError {
+    pub(crate) state: RefState,
+    pub(crate) borrowed_at: Vec<&'static Location<'static>>,
+}
+
+/// Returned by RefCell::try_borrow_mut() when the value is already borrowed,
+/// and by borrow_mut_many() when it's given the same RefCell twice, which
+/// is_duplicate() tells apart
+#[derive(Debug)]
+pub struct BorrowMutError {
+    pub(crate) state: RefState,
+    pub(crate) borrowed_at: Vec<&'static Location<'static>>,
+    // set by borrow_mut_many(), state is RefState::None then
+    pub(crate) duplicate: bool,
+}
+
+impl BorrowError {
+    /// Where the borrows that caused this error were made. Only filled in with
+    /// the debug-borrows feature enabled.
+    pub fn borrowed_at(&self) -> &[&'static Location<'static>] {
+        &self.borrowed_at
+    }
+}
+
+impl BorrowMutError {
+    /// Where the borrows that caused this error were made. Only filled in with
+    /// the debug-borrows feature enabled.
+    pub fn borrowed_at(&self) -> &[&'static Location<'static>] {
+        &self.borrowed_at
+    }
+
+    /// Whether borrow_mut_many() was given the same RefCell twice, rather than
+    /// a cell that was already borrowed
+    pub fn is_duplicate(&self) -> bool {
+        self.duplicate
+    }
+}
+
+fn write_locations(f: &mut fmt::Formatter<'_>, locations: &[&Location<'_>]) -> fmt::Result {
+    for (i, location) in locations.iter().enumerate() {
+        let sep = if i == 0 { " (borrowed at " } else { ", " };
+        write!(f, "{}{}", sep, location)?;
+    }
+    if !locations.is_empty() {
+        write!(f, ")")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for RefState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefState::None => write!(f, "not borrowed"),
+            RefState::Shared(1) => write!(f, "1 shared borrow"),
+            RefState::Shared(n) => write!(f, "{} shared borrows", n),
+            RefState::Exclusive(_) => write!(f, "an exclusive borrow"),
         }
     }
 }
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.state {
+            RefState::Shared(n) => write!(f, "too many shared borrows: {}", n)?,
+            state => write!(f, "already mutably borrowed: blocked by {}", state)?,
+        }
+        write_locations(f, &self.borrowed_at)
+    }
+}
+
+impl fmt::Display for BorrowMutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.duplicate {
+            return write!(f, "the same RefCell was passed twice");
+        }
+        write!(f, "already borrowed: blocked by {}", self.state)?;
+        write_locations(f, &self.borrowed_at)
+    }
+}
+
+impl Error for BorrowError {}
+impl Error for BorrowMutError {}
+
 /*
-Ref<'_,T> and RefMut wrappers(smart pointers) around references to the RefCell
+Ref<'_,T> and RefMut wrappers(smart pointers) around references into the RefCell
 with some lifetime related to the scope in which the reference is valid
 When the reference goes out of scope, our custom drop() is called which deals with
 the decrement of shared/exclusive RefState.
@@ -85,41 +251,281 @@ The reason we want to impl Deref is because a user expects, when calling borrow(
 RefCell<T>, to get a &T, not a weird Ref<'_, T>. If we impl deref however, the
 compiler knows to call * on our type until it reaches the target &Self::Target
 which we define in the trait impl as none other than type Target = T.
+
+A guard doesn't hold on to the whole &RefCell<T>. It's split into two halves:
+a pointer to the value it gives access to, and a handle on the RefCell's state
+(BorrowRef/BorrowRefMut) which is responsible for undoing the borrow on drop.
+That way Ref::map() can swap the pointer for one to a field of T (or anything
+else borrowed from T) while the borrow itself carries over untouched.
 */
 
-pub struct Ref<'a, T> {
-    reference: &'a RefCell<T>,
+// BorrowFlag is the part of the RefCell that the guards need to get at: the
+// RefState, and with the debug-borrows feature the #[track_caller] Location of
+// every Ref/RefMut currently alive. Each guard remembers its own Location and
+// takes it back out of the list on drop.
+struct BorrowFlag {
+    state: Cell<RefState>,
+    #[cfg(feature = "debug-borrows")]
+    borrowed_at: Cell<Vec<&'static Location<'static>>>,
 }
-pub struct RefMut<'a, T> {
-    reference: &'a RefCell<T>,
+
+impl BorrowFlag {
+    fn new() -> Self {
+        Self {
+            state: Cell::new(RefState::None),
+            #[cfg(feature = "debug-borrows")]
+            borrowed_at: Cell::new(Vec::new()),
+        }
+    }
+
+    fn borrowed_at(&self) -> Vec<&'static Location<'static>> {
+        #[cfg(feature = "debug-borrows")]
+        {
+            let locations = self.borrowed_at.take();
+            self.borrowed_at.set(locations.clone());
+            locations
+        }
+        #[cfg(not(feature = "debug-borrows"))]
+        Vec::new()
+    }
+
+    #[cfg(feature = "debug-borrows")]
+    fn push_location(&self, location: &'static Location<'static>) {
+        let mut locations = self.borrowed_at.take();
+        locations.push(location);
+        self.borrowed_at.set(locations);
+    }
+
+    #[cfg(feature = "debug-borrows")]
+    fn remove_location(&self, location: &'static Location<'static>) {
+        let mut locations = self.borrowed_at.take();
+        // several guards can come from the same line, any one of them will do
+        if let Some(i) = locations.iter().position(|l| std::ptr::eq(*l, location)) {
+            locations.swap_remove(i);
+        }
+        self.borrowed_at.set(locations);
+    }
 }
 
-impl<T> std::ops::Deref for Ref<'_, T> {
+// BorrowRef is one share of a RefState::Shared borrow. Creating one increments
+// the count, dropping it decrements the count.
+struct BorrowRef<'a> {
+    flag: &'a BorrowFlag,
+    #[cfg(feature = "debug-borrows")]
+    location: &'static Location<'static>,
+}
+
+impl<'a> BorrowRef<'a> {
+    #[track_caller]
+    fn new(flag: &'a BorrowFlag) -> Result<Self, BorrowError> {
+        match flag.state.get() {
+            RefState::None => flag.state.set(RefState::Shared(1)),
+            RefState::Shared(n) if n < MAX_SHARED => flag.state.set(RefState::Shared(n + 1)),
+            blocked @ RefState::Shared(_) | blocked @ RefState::Exclusive(_) => {
+                return Err(BorrowError {
+                    state: blocked,
+                    borrowed_at: flag.borrowed_at(),
+                })
+            }
+        }
+        Ok(BorrowRef::track(flag))
+    }
+
+    #[track_caller]
+    fn track(flag: &'a BorrowFlag) -> Self {
+        #[cfg(feature = "debug-borrows")]
+        {
+            let location = Location::caller();
+            flag.push_location(location);
+            BorrowRef { flag, location }
+        }
+        #[cfg(not(feature = "debug-borrows"))]
+        BorrowRef { flag }
+    }
+}
+
+impl Clone for BorrowRef<'_> {
+    #[track_caller]
+    fn clone(&self) -> Self {
+        // we already hold a shared borrow so there can't be an exclusive one.
+        // Clone can't fail, so running out of room is a panic, like std's.
+        let state = &self.flag.state;
+        match state.get() {
+            RefState::Shared(n) if n < MAX_SHARED => state.set(RefState::Shared(n + 1)),
+            RefState::Shared(_) => panic!("too many shared borrows"),
+            RefState::None | RefState::Exclusive(_) => unreachable!(),
+        }
+        BorrowRef::track(self.flag)
+    }
+}
+
+impl Drop for BorrowRef<'_> {
+    fn drop(&mut self) {
+        #[cfg(feature = "debug-borrows")]
+        self.flag.remove_location(self.location);
+        // On drop we must decrement the RefState(Shared) count
+        let state = &self.flag.state;
+        match state.get() {
+            RefState::None | RefState::Exclusive(_) => unreachable!(),
+            RefState::Shared(1) => state.set(RefState::None),
+            RefState::Shared(n) => state.set(RefState::Shared(n - 1)),
+        }
+    }
+}
+
+// BorrowRefMut is the handle for an exclusive borrow. It can only be created
+// from RefState::None, and is cloned only by RefMut::map_split(), which hands
+// out disjoint parts of the value, so the borrow stays exclusive.
+struct BorrowRefMut<'a> {
+    flag: &'a BorrowFlag,
+    #[cfg(feature = "debug-borrows")]
+    location: &'static Location<'static>,
+}
+
+impl<'a> BorrowRefMut<'a> {
+    #[track_caller]
+    fn new(flag: &'a BorrowFlag) -> Result<Self, BorrowMutError> {
+        match flag.state.get() {
+            RefState::None => flag.state.set(RefState::Exclusive(1)),
+            blocked => {
+                return Err(BorrowMutError {
+                    state: blocked,
+                    borrowed_at: flag.borrowed_at(),
+                    duplicate: false,
+                })
+            }
+        }
+        Ok(BorrowRefMut::track(flag))
+    }
+
+    #[track_caller]
+    fn track(flag: &'a BorrowFlag) -> Self {
+        #[cfg(feature = "debug-borrows")]
+        {
+            let location = Location::caller();
+            flag.push_location(location);
+            BorrowRefMut { flag, location }
+        }
+        #[cfg(not(feature = "debug-borrows"))]
+        BorrowRefMut { flag }
+    }
+
+    #[track_caller]
+    fn split(&self) -> Self {
+        let state = &self.flag.state;
+        match state.get() {
+            RefState::Exclusive(n) => state.set(RefState::Exclusive(
+                n.checked_add(1).expect("too many RefMut splits"),
+            )),
+            RefState::None | RefState::Shared(_) => unreachable!(),
+        }
+        BorrowRefMut::track(self.flag)
+    }
+}
+
+impl Drop for BorrowRefMut<'_> {
+    fn drop(&mut self) {
+        #[cfg(feature = "debug-borrows")]
+        self.flag.remove_location(self.location);
+        let state = &self.flag.state;
+        match state.get() {
+            RefState::Shared(_) | RefState::None => unreachable!(),
+            RefState::Exclusive(1) => state.set(RefState::None),
+            RefState::Exclusive(n) => state.set(RefState::Exclusive(n - 1)),
+        }
+    }
+}
+
+pub struct Ref<'a, T: ?Sized> {
+    value: NonNull<T>,
+    borrow: BorrowRef<'a>,
+}
+
+// RefMut additionally needs the PhantomData to be invariant over T, just like
+// a &'a mut T is. NonNull<T> on its own is covariant.
+pub struct RefMut<'a, T: ?Sized> {
+    value: NonNull<T>,
+    borrow: BorrowRefMut<'a>,
+    marker: PhantomData<&'a mut T>,
+}
+
+impl<T: ?Sized> std::ops::Deref for Ref<'_, T> {
     // A Ref<> is created only when no exclusive references exist
     // which is checked at runtime rather than at compilation
-    // so dereferencing the *mut T given by UnsafeCell.get()
+    // so dereferencing the pointer into the UnsafeCell
     // and casting it into & is fine
     type Target = T;
     fn deref(&self) -> &Self::Target {
-        unsafe { &*self.reference.val.get() }
+        unsafe { self.value.as_ref() }
     }
 }
 
-impl<T> Drop for Ref<'_, T> {
-    fn drop(&mut self) {
-        // On drop we must decrement the RefState(Shared) count
-        match self.reference.state.get() {
-            RefState::None | RefState::Exclusive => unreachable!(),
-            RefState::Shared(1) => self.reference.state.set(RefState::None),
-            RefState::Shared(n) => self.reference.state.set(RefState::Shared(n - 1)),
+// These are associated functions rather than methods, i.e. Ref::map(r, ..)
+// instead of r.map(..), so they don't shadow methods of the same name on T
+// that are reachable through Deref.
+impl<'a, T: ?Sized> Ref<'a, T> {
+    /// Makes a new Ref to the same value, without going through the RefCell
+    #[allow(clippy::should_implement_trait)]
+    #[track_caller]
+    pub fn clone(orig: &Ref<'a, T>) -> Ref<'a, T> {
+        Ref {
+            value: orig.value,
+            borrow: orig.borrow.clone(),
+        }
+    }
+
+    /// Makes a new Ref for a component of the borrowed data
+    pub fn map<U: ?Sized, F>(orig: Ref<'a, T>, f: F) -> Ref<'a, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        Ref {
+            value: NonNull::from(f(&*orig)),
+            borrow: orig.borrow,
+        }
+    }
+
+    /// Like map(), but the closure may decline, in which case the original
+    /// Ref is handed back.
+    pub fn filter_map<U: ?Sized, F>(orig: Ref<'a, T>, f: F) -> Result<Ref<'a, U>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        match f(&*orig) {
+            Some(value) => Ok(Ref {
+                value: NonNull::from(value),
+                borrow: orig.borrow,
+            }),
+            None => Err(orig),
         }
     }
+
+    /// Splits a Ref into two Refs for different components of the borrowed data
+    #[track_caller]
+    pub fn map_split<U: ?Sized, V: ?Sized, F>(orig: Ref<'a, T>, f: F) -> (Ref<'a, U>, Ref<'a, V>)
+    where
+        F: FnOnce(&T) -> (&U, &V),
+    {
+        let (a, b) = f(&*orig);
+        let borrow = orig.borrow.clone();
+        (
+            Ref {
+                value: NonNull::from(a),
+                borrow,
+            },
+            Ref {
+                value: NonNull::from(b),
+                borrow: orig.borrow,
+            },
+        )
+    }
 }
+
 // RefMut must implement both Deref and DerefMut traits.
-impl<T> std::ops::Deref for RefMut<'_, T> {
+impl<T: ?Sized> std::ops::Deref for RefMut<'_, T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
-        unsafe { &*self.reference.val.get() }
+        unsafe { self.value.as_ref() }
     }
 }
 
@@ -127,17 +533,387 @@ impl<T> std::ops::Deref for RefMut<'_, T> {
 // Once a RefMut is given out, we set the state to Exclusive disallowing
 // any Refs to exist.
 
-impl<T> std::ops::DerefMut for RefMut<'_, T> {
+impl<T: ?Sized> std::ops::DerefMut for RefMut<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.reference.val.get() }
+        unsafe { self.value.as_mut() }
     }
 }
 
-impl<T> Drop for RefMut<'_, T> {
-    fn drop(&mut self) {
-        match self.reference.state.get() {
-            RefState::Shared(_) | RefState::None => unreachable!(),
-            RefState::Exclusive => self.reference.state.set(RefState::None),
+impl<'a, T: ?Sized> RefMut<'a, T> {
+    /// Makes a new RefMut for a component of the borrowed data
+    pub fn map<U: ?Sized, F>(mut orig: RefMut<'a, T>, f: F) -> RefMut<'a, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        RefMut {
+            value: NonNull::from(f(&mut *orig)),
+            borrow: orig.borrow,
+            marker: PhantomData,
+        }
+    }
+
+    /// Like map(), but the closure may decline, in which case the original
+    /// RefMut is handed back.
+    pub fn filter_map<U: ?Sized, F>(mut orig: RefMut<'a, T>, f: F) -> Result<RefMut<'a, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        // we can't return orig from inside the match while f's &mut borrow of it
+        // is still alive, so go through a raw pointer first
+        match f(&mut *orig).map(NonNull::from) {
+            Some(value) => Ok(RefMut {
+                value,
+                borrow: orig.borrow,
+                marker: PhantomData,
+            }),
+            None => Err(orig),
+        }
+    }
+
+    /// Splits a RefMut into two RefMuts for disjoint components of the borrowed
+    /// data. The RefCell stays exclusively borrowed until both are dropped.
+    #[track_caller]
+    pub fn map_split<U: ?Sized, V: ?Sized, F>(
+        mut orig: RefMut<'a, T>,
+        f: F,
+    ) -> (RefMut<'a, U>, RefMut<'a, V>)
+    where
+        F: FnOnce(&mut T) -> (&mut U, &mut V),
+    {
+        let (a, b) = f(&mut *orig);
+        let (a, b) = (NonNull::from(a), NonNull::from(b));
+        let borrow = orig.borrow.split();
+        (
+            RefMut {
+                value: a,
+                borrow,
+                marker: PhantomData,
+            },
+            RefMut {
+                value: b,
+                borrow: orig.borrow,
+                marker: PhantomData,
+            },
+        )
+    }
+}
+
+// Standard traits. Like std, the ones that need to look at the value borrow it
+// and so panic if the RefCell is mutably borrowed at the time, with the one
+// exception of Debug, which prints a placeholder instead, since Debug is what
+// you reach for while tracking down exactly that kind of bug.
+
+impl<T: fmt::Debug> fmt::Debug for RefCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // a placeholder that Debug-prints as a bare <borrowed>
+        struct BorrowedPlaceholder;
+        impl fmt::Debug for BorrowedPlaceholder {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("<borrowed>")
+            }
+        }
+
+        let mut d = f.debug_struct("RefCell");
+        match self.try_borrow() {
+            Ok(borrow) => d.field("value", &&*borrow),
+            Err(_) => d.field("value", &BorrowedPlaceholder),
+        };
+        d.finish()
+    }
+}
+
+impl<T: Default> Default for RefCell<T> {
+    fn default() -> Self {
+        RefCell::new(T::default())
+    }
+}
+
+impl<T: Clone> Clone for RefCell<T> {
+    /// Panics if the value is currently mutably borrowed
+    #[track_caller]
+    fn clone(&self) -> Self {
+        RefCell::new(self.borrow().clone())
+    }
+}
+
+impl<T> From<T> for RefCell<T> {
+    fn from(val: T) -> Self {
+        RefCell::new(val)
+    }
+}
+
+impl<T: PartialEq> PartialEq for RefCell<T> {
+    /// Panics if either value is currently mutably borrowed
+    fn eq(&self, other: &Self) -> bool {
+        *self.borrow() == *other.borrow()
+    }
+}
+
+impl<T: Eq> Eq for RefCell<T> {}
+
+impl<T: PartialOrd> PartialOrd for RefCell<T> {
+    /// Panics if either value is currently mutably borrowed
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.borrow().partial_cmp(&*other.borrow())
+    }
+}
+
+impl<T: Ord> Ord for RefCell<T> {
+    /// Panics if either value is currently mutably borrowed
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.borrow().cmp(&*other.borrow())
+    }
+}
+
+// Ref and RefMut format exactly like the value they point to
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Ref<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Ref<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for RefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{borrow_mut_many, Ref, RefCell, RefMut};
+
+    #[test]
+    fn try_borrow_errors() {
+        let cell = RefCell::new(5);
+        let r1 = cell.borrow();
+        let r2 = cell.try_borrow().unwrap();
+        let err = cell.try_borrow_mut().err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("already borrowed: blocked by 2 shared borrows"));
+        drop((r1, r2));
+
+        let mut w = cell.borrow_mut();
+        *w += 1;
+        let err = cell.try_borrow().err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("already mutably borrowed: blocked by an exclusive borrow"));
+        assert!(cell.try_borrow_mut().is_err());
+        drop(w);
+        assert_eq!(6, *cell.borrow());
+    }
+
+    // MAX_SHARED is lowered to 16 under cfg(test)
+    #[test]
+    fn shared_overflow_is_an_error() {
+        let cell = RefCell::new(0);
+        for _ in 0..16 {
+            std::mem::forget(cell.borrow());
         }
+        let err = cell.try_borrow().err().unwrap();
+        assert!(err.to_string().starts_with("too many shared borrows: 16"));
+        // still no way to get a RefMut past the leaked Refs
+        assert!(cell.try_borrow_mut().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "too many shared borrows")]
+    fn ref_clone_overflow_panics() {
+        let cell = RefCell::new(0);
+        let r = cell.borrow();
+        loop {
+            std::mem::forget(Ref::clone(&r));
+        }
+    }
+
+    #[cfg(feature = "debug-borrows")]
+    #[test]
+    fn errors_report_borrow_locations() {
+        let cell = RefCell::new(0);
+        let r1 = cell.borrow();
+        let line = line!() - 1;
+        let r2 = Ref::clone(&r1);
+        let err = cell.try_borrow_mut().err().unwrap();
+        let lines: Vec<u32> = err.borrowed_at().iter().map(|l| l.line()).collect();
+        assert_eq!(vec![line, line + 2], lines);
+        assert!(err.borrowed_at().iter().all(|l| l.file() == file!()));
+        assert!(err.to_string().contains(&format!("{}:{}", file!(), line)));
+        drop(r1);
+        let err = cell.try_borrow_mut().err().unwrap();
+        assert_eq!(vec![line + 2], vec![err.borrowed_at()[0].line()]);
+        drop(r2);
+
+        let _w = cell.borrow_mut();
+        let line = line!() - 1;
+        let err = cell.try_borrow().err().unwrap();
+        assert_eq!(line, err.borrowed_at()[0].line());
+    }
+
+    #[cfg(not(feature = "debug-borrows"))]
+    #[test]
+    fn no_locations_without_feature() {
+        let cell = RefCell::new(0);
+        let _r = cell.borrow();
+        assert!(cell
+            .try_borrow_mut()
+            .err()
+            .unwrap()
+            .borrowed_at()
+            .is_empty());
+    }
+
+    #[test]
+    fn debug_prints_borrowed_placeholder() {
+        let cell = RefCell::new(vec![1]);
+        assert_eq!("RefCell { value: [1] }", format!("{:?}", cell));
+        let r = cell.borrow();
+        // shared borrows don't get in the way
+        assert_eq!("RefCell { value: [1] }", format!("{:?}", cell));
+        assert_eq!("[1]", format!("{:?}", r));
+        drop(r);
+        let w = cell.borrow_mut();
+        assert_eq!("RefCell { value: <borrowed> }", format!("{:?}", cell));
+        assert_eq!("[1]", format!("{:?}", w));
+    }
+
+    #[test]
+    fn std_traits() {
+        let a = RefCell::new(String::from("a"));
+        let b = RefCell::from(String::from("b"));
+        assert!(a < b);
+        assert_eq!(a, a.clone());
+        assert_eq!(RefCell::new(String::new()), RefCell::default());
+        assert_eq!("b", b.borrow().to_string());
+        assert_eq!("b", format!("{}", b.borrow_mut()));
+    }
+
+    #[test]
+    #[should_panic(expected = "already mutably borrowed")]
+    fn eq_panics_while_mutably_borrowed() {
+        let a = RefCell::new(1);
+        let _w = a.borrow_mut();
+        let _ = a == RefCell::new(1);
+    }
+
+    #[test]
+    fn ref_map() {
+        let cell = RefCell::new((1, String::from("two")));
+        let first = Ref::map(cell.borrow(), |t| &t.0);
+        let second = Ref::map(cell.borrow(), |t| t.1.as_str());
+        assert_eq!(1, *first);
+        assert_eq!("two", &*second);
+        assert!(cell.try_borrow_mut().is_err());
+        drop(first);
+        assert!(cell.try_borrow_mut().is_err());
+        drop(second);
+        assert!(cell.try_borrow_mut().is_ok());
+    }
+
+    #[test]
+    fn ref_filter_map() {
+        let cell = RefCell::new(vec![1, 2, 3]);
+        let found = Ref::filter_map(cell.borrow(), |v| v.get(1)).ok().unwrap();
+        assert_eq!(2, *found);
+        drop(found);
+        let orig = Ref::filter_map(cell.borrow(), |v| v.get(10)).err().unwrap();
+        assert_eq!(3, orig.len());
+        drop(orig);
+
+        let mut last = RefMut::filter_map(cell.borrow_mut(), |v| v.last_mut())
+            .ok()
+            .unwrap();
+        *last = 30;
+        drop(last);
+        assert_eq!(vec![1, 2, 30], *cell.borrow());
+    }
+
+    #[test]
+    fn map_split() {
+        let cell = RefCell::new([1, 2, 3, 4]);
+        let (left, right) = Ref::map_split(cell.borrow(), |a| a.split_at(2));
+        assert_eq!([1, 2], *left);
+        assert_eq!([3, 4], *right);
+        drop(left);
+        assert!(cell.try_borrow_mut().is_err());
+        drop(right);
+
+        let (mut left, mut right) = RefMut::map_split(cell.borrow_mut(), |a| a.split_at_mut(2));
+        left[0] = 10;
+        right[1] = 40;
+        drop(left);
+        // the other half still holds the exclusive borrow
+        assert!(cell.try_borrow().is_err());
+        drop(right);
+        assert_eq!([10, 2, 3, 40], *cell.borrow());
+    }
+
+    #[test]
+    fn ref_mut_map() {
+        let cell = RefCell::new((0, 0));
+        *RefMut::map(cell.borrow_mut(), |t| &mut t.1) = 5;
+        assert_eq!((0, 5), *cell.borrow());
+    }
+
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn borrow_mut_panics_while_shared() {
+        let cell = RefCell::new(String::new());
+        let _r = cell.borrow();
+        let _w = cell.borrow_mut();
+    }
+
+    #[test]
+    fn borrow_mut_many_cells() {
+        let cells: Vec<RefCell<i32>> = (0..4).map(RefCell::new).collect();
+        let [mut a, mut b] = borrow_mut_many([&cells[0], &cells[3]]).unwrap();
+        std::mem::swap(&mut *a, &mut *b);
+        assert!(cells[0].try_borrow().is_err());
+        drop((a, b));
+        assert_eq!(*cells[0].borrow(), 3);
+        assert_eq!(*cells[3].borrow(), 0);
+    }
+
+    #[test]
+    fn borrow_mut_many_duplicate_is_an_error() {
+        let a = RefCell::new(1);
+        let b = RefCell::new(2);
+        let err = borrow_mut_many([&a, &b, &a]).err().unwrap();
+        assert!(err.is_duplicate());
+        assert_eq!(err.to_string(), "the same RefCell was passed twice");
+        assert!(err.borrowed_at().is_empty());
+        assert!(a.try_borrow_mut().is_ok());
+        assert!(b.try_borrow_mut().is_ok());
+    }
+
+    #[test]
+    fn borrow_mut_many_rolls_back() {
+        let a = RefCell::new(1);
+        let b = RefCell::new(2);
+        let c = RefCell::new(3);
+        let held = c.borrow();
+        let err = borrow_mut_many([&a, &b, &c]).err().unwrap();
+        assert!(!err.is_duplicate());
+        assert!(err
+            .to_string()
+            .starts_with("already borrowed: blocked by 1 shared borrow"));
+        // a and b were borrowed before c failed, and are free again
+        assert!(a.try_borrow_mut().is_ok());
+        assert!(b.try_borrow_mut().is_ok());
+        drop(held);
+        assert!(borrow_mut_many([&a, &b, &c]).is_ok());
     }
 }
@@ -0,0 +1,11 @@
+// Thread-safe counterparts of the single-threaded primitives in this crate.
+// Everything in here swaps the Cell<usize>/Cell<RefState> bookkeeping for
+// atomics, which is what lets the types be Send + Sync.
+
+mod arc;
+mod atomic_refcell;
+mod once_lock;
+
+pub use arc::{Arc, Weak};
+pub use atomic_refcell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+pub use once_lock::{OnceLock, SyncLazy};
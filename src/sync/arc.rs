@@ -0,0 +1,480 @@
+// Arc is Rc with atomic reference counts, so that clones can be sent to and
+// dropped on other threads. The layout mirrors rc::RcInner: the value is a
+// ManuallyDrop that is dropped by hand when strong -> 0, and all the strong
+// pointers collectively hold one implicit weak reference, so the allocation is
+// freed when weak -> 0.
+//
+// Memory orderings, the short version:
+// - Incrementing a count can be Relaxed. You can only clone from an existing
+//   Arc/Weak, which already keeps the allocation alive, so there is nothing to
+//   synchronize with.
+// - Decrementing is Release, and whoever brings a count to 0 does an Acquire
+//   fence before tearing down. That makes every access to the value made
+//   through other Arcs (on other threads) happen-before the drop.
+// - get_mut() "locks" the weak count by setting it to usize::MAX while it
+//   checks the strong count, so no Weak can be created (or upgraded) halfway
+//   through the check. See is_unique().
+
+use std::hint;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+
+// Counts past this are treated as a leak gone wrong, e.g. mem::forget(arc.clone())
+// in a loop. We abort instead of letting the count wrap around to 0.
+const MAX_REFCOUNT: usize = isize::MAX as usize;
+
+#[cfg(test)]
+mod model;
+
+// Called before every atomic operation on the counts. It does nothing, except
+// in the model tests, which switch between threads here. `spinning` says the
+// caller is waiting for another thread to make progress.
+#[inline]
+fn yield_point(spinning: bool) {
+    #[cfg(test)]
+    model::switch(spinning);
+}
+
+struct ArcInner<T> {
+    val: ManuallyDrop<T>,
+    strong: AtomicUsize,
+    weak: AtomicUsize,
+}
+
+// Like rc::Rc, the drop checker already knows that dropping an Arc<T> can drop
+// a T because Arc has a Drop impl. The PhantomData makes auto traits and
+// variance follow T as they would for an owned ArcInner<T>, and says in the
+// type that an Arc<T> owns its ArcInner<T>.
+pub struct Arc<T> {
+    inner: NonNull<ArcInner<T>>,
+    phantom: PhantomData<ArcInner<T>>,
+}
+
+// An Arc<T> gives out &T on any thread that holds a clone, so T: Sync is needed.
+// The last Arc to go away drops T on whatever thread it's on, so T: Send too.
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+
+impl<T> Arc<T> {
+    pub fn new(val: T) -> Self {
+        let inner = Box::new(ArcInner {
+            val: ManuallyDrop::new(val),
+            strong: AtomicUsize::new(1),
+            weak: AtomicUsize::new(1),
+        });
+        Self::from_inner(NonNull::from(Box::leak(inner)))
+    }
+
+    fn from_inner(inner: NonNull<ArcInner<T>>) -> Self {
+        Self {
+            inner,
+            phantom: PhantomData,
+        }
+    }
+
+    fn inner(&self) -> &ArcInner<T> {
+        // the allocation is alive as long as there is an Arc around
+        unsafe { self.inner.as_ref() }
+    }
+
+    /// Number of Arc<T> pointing to this allocation. Other threads may change
+    /// this at any time, so it's only a snapshot.
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().strong.load(Ordering::Acquire)
+    }
+
+    /// Number of Weak<T> pointing to this allocation, also only a snapshot.
+    pub fn weak_count(this: &Self) -> usize {
+        match this.inner().weak.load(Ordering::Acquire) {
+            // get_mut() has the weak count locked, which it only can if there
+            // were no Weaks to begin with
+            usize::MAX => 0,
+            n => n - 1,
+        }
+    }
+
+    /// Creates a new Weak<T> pointer to this allocation
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        let inner = this.inner();
+        yield_point(false);
+        let mut cur = inner.weak.load(Ordering::Relaxed);
+        loop {
+            // someone is in the middle of is_unique(), wait for them
+            if cur == usize::MAX {
+                hint::spin_loop();
+                yield_point(true);
+                cur = inner.weak.load(Ordering::Relaxed);
+                continue;
+            }
+            if cur > MAX_REFCOUNT {
+                std::process::abort();
+            }
+            // Acquire to synchronize with the Release store in is_unique()
+            yield_point(false);
+            match inner.weak.compare_exchange_weak(
+                cur,
+                cur + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Weak { inner: this.inner },
+                Err(old) => cur = old,
+            }
+        }
+    }
+
+    /// Returns true if both Arcs point to the same allocation
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.inner == other.inner
+    }
+
+    // True if this is the only Arc and there are no Weaks
+    fn is_unique(&mut self) -> bool {
+        // Lock the weak count so that no Weak can be created while we look at the
+        // strong count. If this fails there is some Weak around.
+        yield_point(false);
+        if self
+            .inner()
+            .weak
+            .compare_exchange(1, usize::MAX, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            // Acquire pairs with the Release decrement in Drop, so any use of
+            // the value through an Arc that has since been dropped is visible
+            yield_point(false);
+            let unique = self.inner().strong.load(Ordering::Acquire) == 1;
+            yield_point(false);
+            self.inner().weak.store(1, Ordering::Release);
+            unique
+        } else {
+            false
+        }
+    }
+
+    /// Returns a mutable reference to the value if there are no other Arcs
+    /// or Weaks pointing to it.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if this.is_unique() {
+            // we have &mut to the only pointer into the allocation
+            Some(unsafe { &mut this.inner.as_mut().val })
+        } else {
+            None
+        }
+    }
+
+    /// Clone-on-write: returns a mutable reference to the value, first cloning
+    /// it into a fresh allocation if it is shared. Any Weaks pointing to the old
+    /// allocation are left behind with it.
+    pub fn make_mut(this: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        if !this.is_unique() {
+            *this = Arc::new((**this).clone());
+        }
+        // either it was unique already or we just made a fresh Arc
+        unsafe { &mut this.inner.as_mut().val }
+    }
+
+    /// Returns the inner value if this is the only Arc, otherwise hands the
+    /// Arc back unchanged.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        yield_point(false);
+        if this
+            .inner()
+            .strong
+            .compare_exchange(1, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(this);
+        }
+        // same as in Drop, see everything the other (now dropped) Arcs did
+        atomic::fence(Ordering::Acquire);
+        let this = ManuallyDrop::new(this);
+        unsafe {
+            let val = ManuallyDrop::take(&mut (*this.inner.as_ptr()).val);
+            // give up the implicit weak reference, like Drop would
+            drop(Weak { inner: this.inner });
+            Ok(val)
+        }
+    }
+}
+
+impl<T> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        yield_point(false);
+        let old = self.inner().strong.fetch_add(1, Ordering::Relaxed);
+        if old > MAX_REFCOUNT {
+            std::process::abort();
+        }
+        Self::from_inner(self.inner)
+    }
+}
+
+impl<T> std::ops::Deref for Arc<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.inner().val
+    }
+}
+
+impl<T> Drop for Arc<T> {
+    fn drop(&mut self) {
+        yield_point(false);
+        if self.inner().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        atomic::fence(Ordering::Acquire);
+        unsafe {
+            ManuallyDrop::drop(&mut (*self.inner.as_ptr()).val);
+        }
+        drop(Weak { inner: self.inner });
+    }
+}
+
+// Weak holds a NonNull, so Weak::new() can't use a null pointer. Like
+// rc::Weak, it uses usize::MAX as a sentinel instead, which can never be the
+// address of a real ArcInner: usize::MAX is odd, and the atomic counts make
+// ArcInner aligned to at least 2 bytes.
+pub struct Weak<T> {
+    inner: NonNull<ArcInner<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Weak<T> {}
+unsafe impl<T: Send + Sync> Sync for Weak<T> {}
+
+impl<T> Weak<T> {
+    /// Creates a Weak<T> that doesn't point to any allocation.
+    /// Calling upgrade() on it always returns None.
+    pub fn new() -> Self {
+        Self {
+            inner: NonNull::new(usize::MAX as *mut ArcInner<T>).expect("usize::MAX is not null"),
+        }
+    }
+
+    fn inner(&self) -> Option<&ArcInner<T>> {
+        if self.inner.as_ptr() as usize == usize::MAX {
+            None
+        } else {
+            Some(unsafe { self.inner.as_ref() })
+        }
+    }
+
+    /// Attempts to get an Arc<T> out of this Weak<T>, returning None if the
+    /// value has already been dropped.
+    pub fn upgrade(&self) -> Option<Arc<T>> {
+        let inner = self.inner()?;
+        // can't just fetch_add here: if strong is 0 the value is gone, and
+        // bumping it back to 1 would resurrect it
+        yield_point(false);
+        let mut cur = inner.strong.load(Ordering::Relaxed);
+        loop {
+            if cur == 0 {
+                return None;
+            }
+            if cur > MAX_REFCOUNT {
+                std::process::abort();
+            }
+            // Acquire to synchronize with get_mut()'s check of the strong count
+            yield_point(false);
+            match inner.strong.compare_exchange_weak(
+                cur,
+                cur + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Arc::from_inner(self.inner)),
+                Err(old) => cur = old,
+            }
+        }
+    }
+
+    /// Number of Arc<T> pointing to this allocation, 0 for Weak::new()
+    pub fn strong_count(&self) -> usize {
+        self.inner()
+            .map_or(0, |inner| inner.strong.load(Ordering::Acquire))
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        if let Some(inner) = self.inner() {
+            // a Weak exists so the weak count can't be locked by get_mut()
+            yield_point(false);
+            let old = inner.weak.fetch_add(1, Ordering::Relaxed);
+            if old > MAX_REFCOUNT {
+                std::process::abort();
+            }
+        }
+        Self { inner: self.inner }
+    }
+}
+
+impl<T> Drop for Weak<T> {
+    fn drop(&mut self) {
+        let inner = match self.inner() {
+            Some(inner) => inner,
+            None => return,
+        };
+        yield_point(false);
+        if inner.weak.fetch_sub(1, Ordering::Release) == 1 {
+            atomic::fence(Ordering::Acquire);
+            // val was already dropped by the last Arc, this only frees memory
+            unsafe {
+                let _ = Box::from_raw(self.inner.as_ptr());
+            }
+        }
+    }
+}
+
+// The model_* tests run every interleaving of the atomic operations in two
+// threads, see model.rs. That covers races like the last drop vs upgrade(), but
+// only with SeqCst semantics, so the other concurrent tests also hammer the
+// same races on real threads, where a wrong ordering or a double free can show
+// up, especially under a sanitizer or Miri.
+#[cfg(test)]
+mod tests {
+    use super::{model, Arc, Weak};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    struct DropCounter<'a>(&'a AtomicUsize);
+    impl Drop for DropCounter<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn clone_and_drop_across_threads() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let arc = Arc::new(DropCounter(&DROPS));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let arc = Arc::clone(&arc);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let _clone = Arc::clone(&arc);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(1, Arc::strong_count(&arc));
+        drop(arc);
+        assert_eq!(1, DROPS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn upgrade_races_last_drop() {
+        for _ in 0..200 {
+            let arc = Arc::new(String::from("racy"));
+            let weak = Arc::downgrade(&arc);
+            let t = thread::spawn(move || {
+                // either we see the value intact or we see it gone
+                if let Some(s) = weak.upgrade() {
+                    assert_eq!("racy", *s);
+                }
+            });
+            drop(arc);
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn model_upgrade_vs_last_drop() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let runs = model::check(|| {
+            DROPS.store(0, Ordering::SeqCst);
+            let arc = Arc::new(DropCounter(&DROPS));
+            let weak = Arc::downgrade(&arc);
+            let drop_last: Box<dyn FnOnce() + Send> = Box::new(move || drop(arc));
+            let upgrade: Box<dyn FnOnce() + Send> = Box::new(move || {
+                // an upgraded Arc must still have its value, and keeps it
+                // alive until it's dropped
+                if let Some(arc) = weak.upgrade() {
+                    assert_eq!(0, DROPS.load(Ordering::SeqCst));
+                    drop(arc);
+                }
+            });
+            vec![drop_last, upgrade]
+        });
+        assert!(runs > 1);
+        assert_eq!(1, DROPS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn model_get_mut_vs_downgrade() {
+        static WEAK_DONE: AtomicUsize = AtomicUsize::new(0);
+        let runs = model::check(|| {
+            WEAK_DONE.store(0, Ordering::SeqCst);
+            let mut a = Arc::new(0);
+            let b = Arc::clone(&a);
+            let get_mut: Box<dyn FnOnce() + Send> = Box::new(move || {
+                // only possible once the other thread has dropped both b and
+                // the Weak it made from it
+                if let Some(val) = Arc::get_mut(&mut a) {
+                    assert_eq!(1, WEAK_DONE.load(Ordering::SeqCst));
+                    *val += 1;
+                }
+            });
+            let downgrade: Box<dyn FnOnce() + Send> = Box::new(move || {
+                let weak = Arc::downgrade(&b);
+                drop(b);
+                if let Some(b) = weak.upgrade() {
+                    assert_eq!(0, *b);
+                }
+                drop(weak);
+                WEAK_DONE.store(1, Ordering::SeqCst);
+            });
+            vec![get_mut, downgrade]
+        });
+        assert!(runs > 1);
+    }
+
+    #[test]
+    fn get_mut_and_make_mut() {
+        let mut a = Arc::new(vec![1]);
+        Arc::get_mut(&mut a).unwrap().push(2);
+
+        let weak = Arc::downgrade(&a);
+        assert!(Arc::get_mut(&mut a).is_none());
+        drop(weak);
+
+        let b = Arc::clone(&a);
+        Arc::make_mut(&mut a).push(3);
+        assert!(!Arc::ptr_eq(&a, &b));
+        assert_eq!(vec![1, 2, 3], *a);
+        assert_eq!(vec![1, 2], *b);
+    }
+
+    #[test]
+    fn try_unwrap() {
+        let a = Arc::new(String::from("only"));
+        let b = Arc::clone(&a);
+        let a = Arc::try_unwrap(a).err().unwrap();
+        drop(b);
+        assert_eq!("only", Arc::try_unwrap(a).ok().unwrap());
+    }
+
+    #[test]
+    fn weak_new_and_counts() {
+        let empty: Weak<i32> = Weak::new();
+        assert!(empty.upgrade().is_none());
+        let a = Arc::new(5);
+        let w = Arc::downgrade(&a);
+        let _w2 = w.clone();
+        assert_eq!(2, Arc::weak_count(&a));
+        assert_eq!(1, w.strong_count());
+    }
+}
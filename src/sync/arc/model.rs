@@ -0,0 +1,160 @@
+// A tiny model checker for the tests, in the spirit of loom but much dumber.
+// Arc calls switch() right before each atomic operation on the counts. Outside
+// of check() that does nothing, but threads started by check() only run one at
+// a time, and at every switch() the scheduler picks which thread goes next.
+// check() runs the closures over and over until every sequence of those picks
+// has been tried, so every interleaving of the atomic operations happens once.
+//
+// Each run executes the operations in one global order, i.e. as if they were
+// all SeqCst. Weaker orderings allow more than that, and this doesn't explore
+// any of it, so it finds interleaving bugs (a missing CAS, a check and an
+// update that should be one step), not ordering bugs.
+
+use std::cell::RefCell;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+struct Scheduler {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+struct State {
+    // the thread that is allowed to run
+    running: usize,
+    finished: Vec<bool>,
+    // the picks to replay, then always the first choice after those
+    replay: Vec<usize>,
+    // (pick, number of choices) for every pick made in this run
+    picks: Vec<(usize, usize)>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<(Arc<Scheduler>, usize)>> = const { RefCell::new(None) };
+}
+
+impl Scheduler {
+    // Hands over to the next thread. `me` is the thread that is running now, and
+    // it's only picked again if `me_again` is true.
+    fn pick_next(&self, state: &mut State, me: usize, me_again: bool) {
+        let mut choices: Vec<usize> = (0..state.finished.len())
+            .filter(|&t| !state.finished[t] && (me_again || t != me))
+            .collect();
+        if choices.is_empty() {
+            if !state.finished[me] {
+                // me is spinning and nobody else can run to let it out
+                choices.push(me);
+            } else {
+                return;
+            }
+        }
+        let n = state.picks.len();
+        let pick = if n < state.replay.len() {
+            state.replay[n]
+        } else {
+            0
+        };
+        state.picks.push((pick, choices.len()));
+        state.running = choices[pick];
+        self.wake.notify_all();
+    }
+
+    fn wait_turn<'a>(
+        &self,
+        mut state: std::sync::MutexGuard<'a, State>,
+        me: usize,
+    ) -> std::sync::MutexGuard<'a, State> {
+        while state.running != me {
+            state = self.wake.wait(state).unwrap();
+        }
+        state
+    }
+}
+
+// Called by Arc before each atomic operation. `spinning` means the caller is
+// waiting for another thread, so picking it again wouldn't get anywhere.
+pub(super) fn switch(spinning: bool) {
+    // Arcs dropped by thread local destructors may get here after CURRENT is gone
+    let current = CURRENT
+        .try_with(|current| current.borrow().clone())
+        .ok()
+        .flatten();
+    if let Some((scheduler, me)) = current {
+        let mut state = scheduler.state.lock().unwrap();
+        scheduler.pick_next(&mut state, me, !spinning);
+        drop(scheduler.wait_turn(state, me));
+    }
+}
+
+// Marks the thread as finished and hands over, even when it panicked, so the
+// other threads don't wait for it forever
+struct Finish(Arc<Scheduler>, usize);
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        let mut state = self.0.state.lock().unwrap();
+        state.finished[self.1] = true;
+        self.0.pick_next(&mut state, self.1, false);
+    }
+}
+
+// Runs the closures returned by `setup` on threads of their own, once for
+// every possible interleaving. Returns the number of interleavings.
+pub(super) fn check<F>(setup: impl Fn() -> Vec<F>) -> usize
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut replay = Vec::new();
+    let mut runs = 0;
+    loop {
+        let threads = setup();
+        let scheduler = Arc::new(Scheduler {
+            state: Mutex::new(State {
+                running: usize::MAX,
+                finished: vec![false; threads.len()],
+                replay: replay.clone(),
+                picks: Vec::new(),
+            }),
+            wake: Condvar::new(),
+        });
+        let handles: Vec<_> = threads
+            .into_iter()
+            .enumerate()
+            .map(|(me, f)| {
+                let scheduler = scheduler.clone();
+                thread::spawn(move || {
+                    let state = scheduler.state.lock().unwrap();
+                    drop(scheduler.wait_turn(state, me));
+                    CURRENT.with(|current| *current.borrow_mut() = Some((scheduler.clone(), me)));
+                    let _finish = Finish(scheduler, me);
+                    f();
+                })
+            })
+            .collect();
+        {
+            let mut state = scheduler.state.lock().unwrap();
+            scheduler.pick_next(&mut state, 0, true);
+        }
+        for handle in handles {
+            if let Err(panic) = handle.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+        runs += 1;
+
+        // the next run takes the next choice at the last pick that has one left
+        let mut picks = scheduler.state.lock().unwrap().picks.clone();
+        loop {
+            match picks.pop() {
+                Some((pick, choices)) if pick + 1 < choices => {
+                    replay = picks.iter().map(|&(pick, _)| pick).collect();
+                    replay.push(pick + 1);
+                    break;
+                }
+                Some(_) => {}
+                None => return runs,
+            }
+        }
+    }
+}
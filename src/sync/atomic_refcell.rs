@@ -0,0 +1,210 @@
+// AtomicRefCell is RefCell with the Cell<RefState> swapped for an AtomicUsize,
+// so it can be shared between threads. Borrowing never blocks: if the state
+// doesn't allow the borrow we fail right away, just like RefCell does. That
+// makes it a lock-free reader-writer "lock" for data that is rarely contended.
+//
+// RefState gets packed into the usize as follows:
+//   0                  => RefState::None
+//   1..EXCLUSIVE       => RefState::Shared(n)
+//   EXCLUSIVE          => RefState::Exclusive(1)
+// EXCLUSIVE is the top bit, so the shared count can never run into it without
+// first hitting MAX_SHARED, which we check for.
+
+use crate::refcell::{BorrowError, BorrowMutError, RefState};
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const EXCLUSIVE: usize = !(usize::MAX >> 1);
+const MAX_SHARED: usize = EXCLUSIVE - 1;
+
+fn decode(raw: usize) -> RefState {
+    match raw {
+        0 => RefState::None,
+        EXCLUSIVE => RefState::Exclusive(1),
+        n => RefState::Shared(n),
+    }
+}
+
+pub struct AtomicRefCell<T> {
+    val: UnsafeCell<T>,
+    state: AtomicUsize,
+}
+
+// Sharing an &AtomicRefCell<T> between threads lets any of them get a &mut T
+// (so T: Send) or a &T (so T: Sync). Send comes for free from UnsafeCell.
+unsafe impl<T: Send + Sync> Sync for AtomicRefCell<T> {}
+
+impl<T> AtomicRefCell<T> {
+    pub fn new(val: T) -> Self {
+        Self {
+            val: UnsafeCell::new(val),
+            state: AtomicUsize::new(0),
+        }
+    }
+
+    /// Immutably borrows the value, failing if it is currently mutably borrowed
+    pub fn try_borrow(&self) -> Result<AtomicRef<'_, T>, BorrowError> {
+        let mut cur = self.state.load(Ordering::Relaxed);
+        loop {
+            if cur == EXCLUSIVE {
+                return Err(BorrowError {
+                    state: decode(cur),
+                    borrowed_at: Vec::new(),
+                });
+            }
+            // forgetting AtomicRefs in a loop could otherwise carry the shared
+            // count into the EXCLUSIVE bit
+            if cur == MAX_SHARED {
+                std::process::abort();
+            }
+            // Acquire pairs with the Release in AtomicRefMut's drop, so we see
+            // every write made through the last exclusive borrow
+            match self.state.compare_exchange_weak(
+                cur,
+                cur + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(AtomicRef { reference: self }),
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+
+    /// Mutably borrows the value, failing if it is currently borrowed at all
+    pub fn try_borrow_mut(&self) -> Result<AtomicRefMut<'_, T>, BorrowMutError> {
+        // only RefState::None can become Exclusive, no need for a loop
+        match self
+            .state
+            .compare_exchange(0, EXCLUSIVE, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => Ok(AtomicRefMut { reference: self }),
+            Err(actual) => Err(BorrowMutError {
+                state: decode(actual),
+                borrowed_at: Vec::new(),
+                duplicate: false,
+            }),
+        }
+    }
+
+    /// Like RefCell::borrow(), panics instead of returning an error
+    #[track_caller]
+    pub fn borrow(&self) -> AtomicRef<'_, T> {
+        match self.try_borrow() {
+            Ok(r) => r,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Like RefCell::borrow_mut(), panics instead of returning an error
+    #[track_caller]
+    pub fn borrow_mut(&self) -> AtomicRefMut<'_, T> {
+        match self.try_borrow_mut() {
+            Ok(r) => r,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.val.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.val.into_inner()
+    }
+}
+
+// The guards hold on to the whole cell, like the original Ref/RefMut did.
+// Their Send/Sync follows from &AtomicRefCell<T>, i.e. from T: Send + Sync.
+
+pub struct AtomicRef<'a, T> {
+    reference: &'a AtomicRefCell<T>,
+}
+
+pub struct AtomicRefMut<'a, T> {
+    reference: &'a AtomicRefCell<T>,
+}
+
+impl<T> std::ops::Deref for AtomicRef<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.reference.val.get() }
+    }
+}
+
+impl<T> Drop for AtomicRef<'_, T> {
+    fn drop(&mut self) {
+        // Release so our reads happen-before the writes of whoever gets the
+        // next exclusive borrow, otherwise we could read a value from the future
+        self.reference.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T> std::ops::Deref for AtomicRefMut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.reference.val.get() }
+    }
+}
+
+impl<T> std::ops::DerefMut for AtomicRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.reference.val.get() }
+    }
+}
+
+impl<T> Drop for AtomicRefMut<'_, T> {
+    fn drop(&mut self) {
+        // we were the only borrow, so there's nothing to count down from
+        self.reference.state.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AtomicRefCell;
+    use std::thread;
+
+    #[test]
+    fn borrow_states() {
+        let cell = AtomicRefCell::new(1);
+        let r1 = cell.borrow();
+        let r2 = cell.borrow();
+        let err = cell.try_borrow_mut().err().unwrap();
+        assert_eq!(
+            "already borrowed: blocked by 2 shared borrows",
+            err.to_string()
+        );
+        drop((r1, r2));
+
+        let mut w = cell.borrow_mut();
+        *w = 2;
+        assert!(cell.try_borrow().is_err());
+        assert!(cell.try_borrow_mut().is_err());
+        drop(w);
+        assert_eq!(2, *cell.borrow());
+    }
+
+    #[test]
+    fn shared_across_threads() {
+        let cell = AtomicRefCell::new(0usize);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut done = 0;
+                    // spin on try_borrow_mut, every thread gets 1000 increments in
+                    while done < 1000 {
+                        if let Ok(mut w) = cell.try_borrow_mut() {
+                            *w += 1;
+                            done += 1;
+                        }
+                        if let Ok(r) = cell.try_borrow() {
+                            assert!(*r <= 4000);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(4000, cell.into_inner());
+    }
+}
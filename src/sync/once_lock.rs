@@ -0,0 +1,293 @@
+// OnceLock is the thread-safe version of oncecell::OnceCell. Several threads
+// can race to initialize it, exactly one of them runs its init closure and the
+// others wait for it to finish and then all see the same value.
+//
+// The cell moves through three states, kept in an AtomicU8:
+//   INCOMPLETE -> RUNNING    one thread won the compare_exchange and is now
+//                            running its init closure
+//   RUNNING    -> COMPLETE   the value has been written, everyone may read it
+//   RUNNING    -> INCOMPLETE the init closure failed or panicked, the next
+//                            caller gets to try again
+// Threads that find the state RUNNING spin (yielding to the scheduler) until
+// it changes. The value is only ever written by the RUNNING thread and only
+// ever read once the state is COMPLETE, with Release/Acquire on the state
+// making the write visible to the readers.
+//
+// Unlike OnceCell we can't tell a reentrant init from another thread's init,
+// so initializing a OnceLock from inside its own init closure deadlocks.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+pub struct OnceLock<T> {
+    state: AtomicU8,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Any thread can end up running the init closure and storing the T, or get a
+// &T out of a shared OnceLock, so T: Send + Sync.
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+unsafe impl<T: Send> Send for OnceLock<T> {}
+
+// Puts the state back to INCOMPLETE if the init closure bails out, by error
+// or by panic. On success it's forgotten and the state set to COMPLETE instead.
+struct RunningGuard<'a>(&'a AtomicU8);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(INCOMPLETE, Ordering::Release);
+    }
+}
+
+impl<T> OnceLock<T> {
+    /// Creates an empty OnceLock. This is a const fn so that it can be used
+    /// to initialize a static.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            val: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, or None if it isn't initialized yet. Doesn't wait
+    /// for an initialization in progress on another thread.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            // COMPLETE means the value has been written and will never change
+            Some(unsafe { (*self.val.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if *self.state.get_mut() == COMPLETE {
+            Some(unsafe { self.val.get_mut().assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    /// Initializes the cell with `val`, waiting for any initialization in
+    /// progress. Hands `val` back if the cell ends up initialized by someone else.
+    pub fn set(&self, val: T) -> Result<(), T> {
+        let mut val = Some(val);
+        self.get_or_init(|| val.take().unwrap());
+        match val {
+            None => Ok(()),
+            Some(val) => Err(val),
+        }
+    }
+
+    /// Returns the value, initializing it with `f` first if needed. If another
+    /// thread is initializing it already, waits for that instead of calling `f`.
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        enum Never {}
+        match self.get_or_try_init(|| Ok::<T, Never>(f())) {
+            Ok(val) => val,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Like get_or_init(), but `f` may fail, in which case the cell stays
+    /// uninitialized and one of the waiting threads gets to try next.
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        loop {
+            // Acquire on success isn't needed to write the value, but it keeps
+            // us ordered after a previous failed attempt
+            match self.state.compare_exchange_weak(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(COMPLETE) => return Ok(self.get().unwrap()),
+                // RUNNING, or a spurious failure of compare_exchange_weak
+                Err(_) => thread::yield_now(),
+            }
+        }
+        // we're the only thread in the RUNNING state, no one else touches val
+        let guard = RunningGuard(&self.state);
+        let val = f()?;
+        unsafe { (*self.val.get()).write(val) };
+        std::mem::forget(guard);
+        // Release so that whoever sees COMPLETE also sees the write above
+        self.state.store(COMPLETE, Ordering::Release);
+        Ok(self.get().unwrap())
+    }
+
+    /// Takes the value out, leaving the cell uninitialized
+    pub fn take(&mut self) -> Option<T> {
+        if *self.state.get_mut() == COMPLETE {
+            *self.state.get_mut() = INCOMPLETE;
+            Some(unsafe { self.val.get_mut().assume_init_read() })
+        } else {
+            None
+        }
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        // MaybeUninit never drops its contents on its own
+        drop(self.take());
+    }
+}
+
+// SyncLazy pairs a OnceLock with the function that initializes it, for
+// globals like `static CONFIG: SyncLazy<Config> = SyncLazy::new(load_config);`.
+// The function sits in an UnsafeCell that is only touched from inside the init
+// closure, and the OnceLock makes sure only one thread at a time runs that.
+
+pub struct SyncLazy<T, F = fn() -> T> {
+    once: OnceLock<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+// F is moved out and called on whichever thread wins the race, so F: Send
+unsafe impl<T: Send + Sync, F: Send> Sync for SyncLazy<T, F> {}
+
+impl<T, F> SyncLazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: OnceLock::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> SyncLazy<T, F> {
+    /// Forces evaluation and returns a reference to the value. Panics if the
+    /// init function panicked on an earlier attempt.
+    pub fn force(this: &Self) -> &T {
+        this.once.get_or_init(|| {
+            // we're the RUNNING thread, see the OnceLock state machine
+            match unsafe { (*this.init.get()).take() } {
+                Some(f) => f(),
+                None => panic!("SyncLazy instance has previously been poisoned"),
+            }
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> std::ops::Deref for SyncLazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        SyncLazy::force(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OnceLock, SyncLazy};
+    use std::collections::HashMap;
+    use std::panic;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn exactly_one_initializer_wins() {
+        for _ in 0..100 {
+            let lock = OnceLock::new();
+            let calls = AtomicUsize::new(0);
+            let barrier = Barrier::new(8);
+            let seen: Vec<usize> = thread::scope(|s| {
+                let handles: Vec<_> = (0..8)
+                    .map(|i| {
+                        let (lock, calls, barrier) = (&lock, &calls, &barrier);
+                        s.spawn(move || {
+                            barrier.wait();
+                            *lock.get_or_init(|| {
+                                calls.fetch_add(1, Ordering::SeqCst);
+                                i
+                            })
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            });
+            assert_eq!(1, calls.load(Ordering::SeqCst));
+            assert!(seen.iter().all(|&v| v == seen[0]));
+        }
+    }
+
+    #[test]
+    fn set_races_get_or_init() {
+        let lock = OnceLock::new();
+        let results: Vec<bool> = thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let lock = &lock;
+                    s.spawn(move || lock.set(i).is_ok())
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(1, results.iter().filter(|&&ok| ok).count());
+        assert!(lock.get().is_some());
+    }
+
+    #[test]
+    fn failed_init_can_be_retried() {
+        let lock: OnceLock<String> = OnceLock::new();
+        assert_eq!(Err("fail"), lock.get_or_try_init(|| Err("fail")));
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            lock.get_or_init(|| panic!("boom"))
+        }));
+        assert!(res.is_err());
+        assert!(lock.get().is_none());
+        assert_eq!("ok", lock.get_or_init(|| String::from("ok")));
+    }
+
+    #[test]
+    fn global_config() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static CONFIG: SyncLazy<HashMap<&str, u32>> = SyncLazy::new(|| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            let mut map = HashMap::new();
+            map.insert("workers", 4);
+            map
+        });
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| assert_eq!(Some(&4), CONFIG.get("workers")));
+            }
+        });
+        assert_eq!(1, CALLS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn drops_value() {
+        let mut lock = OnceLock::new();
+        lock.set(vec![1]).unwrap();
+        lock.get_mut().unwrap().push(2);
+        assert_eq!(Some(vec![1, 2]), lock.take());
+        assert!(lock.get().is_none());
+        lock.set(vec![3]).unwrap();
+        assert_eq!(Some(vec![3]), lock.into_inner());
+    }
+}
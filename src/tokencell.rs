@@ -0,0 +1,340 @@
+// Token cells move RefCell's borrow check from run time to compile time.
+// A cell doesn't track its own borrows at all. Instead, every cell belongs to
+// an owner, and the only way to get at the value is through a borrow of that
+// owner: owner.ro(&cell) borrows the owner immutably and returns a &T,
+// owner.rw(&cell) borrows it mutably and returns a &mut T. As long as there is
+// only ever one owner for a set of cells, the borrow checker's rules for the
+// owner carry over to all of its cells: while one &mut T is around, the owner
+// is mutably borrowed, so no other reference into any of its cells can exist.
+// The cells themselves are just an UnsafeCell, with no flag to check.
+//
+// The hard part is making sure there is only one owner. The two cells here do
+// that in different ways:
+// - TCell<Q, T> belongs to the TCellOwner<Q> for the marker type Q. Only one
+//   TCellOwner<Q> can exist at a time, which is checked once, when the owner
+//   is created, against a global list of marker types in use. That list goes
+//   by TypeId, so Q has to be invariant: for<'a> fn(&'a ()) and
+//   fn(&'static ()) have different TypeIds, but the first is a subtype of the
+//   second, and with a covariant Q an owner for it could be turned into a
+//   second owner for the other.
+// - LCell<'id, T> belongs to the LCellOwner<'id> with the same lifetime 'id.
+//   LCellOwner::scope() hands out the owner to a closure that has to work for
+//   any 'id, so the lifetime is a fresh one that no other owner has. 'id is
+//   invariant, so the compiler can't shrink or stretch it to match another
+//   owner's.
+
+use std::any::TypeId;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+// rw2() hands out two &muts, which must not be to the same value
+fn check_distinct<T, U>(a: &UnsafeCell<T>, b: &UnsafeCell<U>) {
+    assert!(
+        a as *const _ as *const u8 != b as *const _ as *const u8,
+        "rw2() called with the same cell twice"
+    );
+}
+
+/// A cell whose value can only be accessed through the TCellOwner<Q>
+pub struct TCell<Q, T: ?Sized> {
+    // invariant in Q (see the top of the file), and Send/Sync regardless of Q,
+    // which is only a marker
+    owner: Marker<Q>,
+    val: UnsafeCell<T>,
+}
+
+// Sharing a TCell between threads shares the value, but all access goes
+// through the one owner, which has to be sent to a thread to use it there.
+unsafe impl<Q, T: ?Sized + Send + Sync> Sync for TCell<Q, T> {}
+
+impl<Q, T> TCell<Q, T> {
+    pub fn new(val: T) -> Self {
+        TCell {
+            owner: PhantomData,
+            val: UnsafeCell::new(val),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.val.into_inner()
+    }
+}
+
+impl<Q, T: ?Sized> TCell<Q, T> {
+    /// Borrows the value without the owner, which is fine since &mut self
+    /// already guarantees there are no other references
+    pub fn get_mut(&mut self) -> &mut T {
+        self.val.get_mut()
+    }
+}
+
+// fn(Q) -> Q is both covariant and contravariant in Q, i.e. invariant, like Id
+// below for LCell's lifetime
+type Marker<Q> = PhantomData<fn(Q) -> Q>;
+
+// Marker types whose TCellOwner currently exists
+static TCELL_OWNERS: Mutex<Vec<TypeId>> = Mutex::new(Vec::new());
+
+/// The owner of all the TCell<Q, _>s. At most one exists at a time for each
+/// marker type Q.
+///
+/// Borrowing two cells mutably through the owner at once doesn't compile:
+///
+/// ```compile_fail,E0499
+/// use UnsafeCells::tokencell::{TCell, TCellOwner};
+///
+/// struct Marker;
+/// let mut owner = TCellOwner::<Marker>::new();
+/// let a = TCell::new(1);
+/// let b = TCell::new(2);
+/// let a_mut = owner.rw(&a);
+/// let b_mut = owner.rw(&b);
+/// *a_mut += *b_mut;
+/// ```
+///
+/// and neither does reading a cell while another one is mutably borrowed:
+///
+/// ```compile_fail,E0502
+/// use UnsafeCells::tokencell::{TCell, TCellOwner};
+///
+/// struct Marker;
+/// let mut owner = TCellOwner::<Marker>::new();
+/// let a = TCell::new(1);
+/// let b = TCell::new(2);
+/// let a_mut = owner.rw(&a);
+/// *a_mut = *owner.ro(&b);
+/// ```
+///
+/// An owner can't be passed off as the owner for a supertype of its marker,
+/// which would be a second owner for that type:
+///
+/// ```compile_fail,E0308
+/// use UnsafeCells::tokencell::TCellOwner;
+///
+/// let a = TCellOwner::<for<'a> fn(&'a ())>::new();
+/// let b = TCellOwner::<fn(&'static ())>::new();
+/// let a: TCellOwner<fn(&'static ())> = a;
+/// ```
+pub struct TCellOwner<Q: 'static> {
+    marker: Marker<Q>,
+}
+
+impl<Q: 'static> TCellOwner<Q> {
+    /// Creates the owner for Q, panicking if one already exists
+    // no Default, creating an owner isn't something that should happen implicitly
+    #[allow(clippy::new_without_default)]
+    #[track_caller]
+    pub fn new() -> Self {
+        match Self::try_new() {
+            Some(owner) => owner,
+            None => panic!(
+                "a TCellOwner<{}> already exists",
+                std::any::type_name::<Q>()
+            ),
+        }
+    }
+
+    /// Creates the owner for Q, or returns None if one already exists
+    pub fn try_new() -> Option<Self> {
+        let mut owners = TCELL_OWNERS.lock().unwrap_or_else(|e| e.into_inner());
+        if owners.contains(&TypeId::of::<Q>()) {
+            return None;
+        }
+        owners.push(TypeId::of::<Q>());
+        Some(TCellOwner {
+            marker: PhantomData,
+        })
+    }
+
+    pub fn ro<'a, T: ?Sized>(&'a self, cell: &'a TCell<Q, T>) -> &'a T {
+        unsafe { &*cell.val.get() }
+    }
+
+    pub fn rw<'a, T: ?Sized>(&'a mut self, cell: &'a TCell<Q, T>) -> &'a mut T {
+        unsafe { &mut *cell.val.get() }
+    }
+
+    /// Mutably borrows two different cells at once, panicking if they are the
+    /// same cell
+    #[track_caller]
+    pub fn rw2<'a, T, U>(
+        &'a mut self,
+        a: &'a TCell<Q, T>,
+        b: &'a TCell<Q, U>,
+    ) -> (&'a mut T, &'a mut U) {
+        check_distinct(&a.val, &b.val);
+        unsafe { (&mut *a.val.get(), &mut *b.val.get()) }
+    }
+}
+
+impl<Q: 'static> Drop for TCellOwner<Q> {
+    fn drop(&mut self) {
+        let mut owners = TCELL_OWNERS.lock().unwrap_or_else(|e| e.into_inner());
+        owners.retain(|&id| id != TypeId::of::<Q>());
+    }
+}
+
+// The invariant lifetime that ties LCells to their owner. fn(&'id ()) -> &'id ()
+// is both covariant and contravariant in 'id, i.e. invariant.
+type Id<'id> = PhantomData<fn(&'id ()) -> &'id ()>;
+
+/// A cell whose value can only be accessed through the LCellOwner<'id>
+pub struct LCell<'id, T: ?Sized> {
+    id: Id<'id>,
+    val: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for LCell<'_, T> {}
+
+impl<'id, T> LCell<'id, T> {
+    pub fn new(val: T) -> Self {
+        LCell {
+            id: PhantomData,
+            val: UnsafeCell::new(val),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.val.into_inner()
+    }
+}
+
+impl<T: ?Sized> LCell<'_, T> {
+    pub fn get_mut(&mut self) -> &mut T {
+        self.val.get_mut()
+    }
+}
+
+/// The owner of all the LCell<'id, _>s, created with LCellOwner::scope().
+///
+/// Borrowing two cells mutably through the owner at once doesn't compile:
+///
+/// ```compile_fail,E0499
+/// use UnsafeCells::tokencell::{LCell, LCellOwner};
+///
+/// LCellOwner::scope(|mut owner| {
+///     let a = LCell::new(1);
+///     let b = LCell::new(2);
+///     let a_mut = owner.rw(&a);
+///     let b_mut = owner.rw(&b);
+///     *a_mut += *b_mut;
+/// });
+/// ```
+///
+/// and a cell can't be used with another scope's owner:
+///
+/// ```compile_fail,E0521
+/// use UnsafeCells::tokencell::{LCell, LCellOwner};
+///
+/// LCellOwner::scope(|owner| {
+///     let cell = LCell::new(1);
+///     owner.ro(&cell);
+///     LCellOwner::scope(|mut other| {
+///         *other.rw(&cell) += 1;
+///     });
+/// });
+/// ```
+pub struct LCellOwner<'id> {
+    id: Id<'id>,
+}
+
+impl<'id> LCellOwner<'id> {
+    /// Calls `f` with a new owner, whose lifetime 'id is unique to this call
+    pub fn scope<R>(f: impl for<'scope> FnOnce(LCellOwner<'scope>) -> R) -> R {
+        f(LCellOwner { id: PhantomData })
+    }
+
+    pub fn ro<'a, T: ?Sized>(&'a self, cell: &'a LCell<'id, T>) -> &'a T {
+        unsafe { &*cell.val.get() }
+    }
+
+    pub fn rw<'a, T: ?Sized>(&'a mut self, cell: &'a LCell<'id, T>) -> &'a mut T {
+        unsafe { &mut *cell.val.get() }
+    }
+
+    /// Mutably borrows two different cells at once, panicking if they are the
+    /// same cell
+    #[track_caller]
+    pub fn rw2<'a, T, U>(
+        &'a mut self,
+        a: &'a LCell<'id, T>,
+        b: &'a LCell<'id, U>,
+    ) -> (&'a mut T, &'a mut U) {
+        check_distinct(&a.val, &b.val);
+        unsafe { (&mut *a.val.get(), &mut *b.val.get()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rc::Rc;
+    use std::thread;
+
+    #[test]
+    fn tcell_owner_is_unique() {
+        struct Marker;
+        let mut owner = TCellOwner::<Marker>::new();
+        assert!(TCellOwner::<Marker>::try_new().is_none());
+
+        let a = Rc::new(TCell::new(1));
+        let b = a.clone();
+        *owner.rw(&a) += 1;
+        assert_eq!(*owner.ro(&b), 2);
+
+        drop(owner);
+        let owner = TCellOwner::<Marker>::try_new().unwrap();
+        assert_eq!(*owner.ro(&a), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "already exists")]
+    fn second_tcell_owner_panics() {
+        struct Marker;
+        let _owner = TCellOwner::<Marker>::new();
+        TCellOwner::<Marker>::new();
+    }
+
+    #[test]
+    fn tcell_rw2() {
+        struct Marker;
+        let mut owner = TCellOwner::<Marker>::new();
+        let cells: Vec<TCell<Marker, i32>> = (0..3).map(TCell::new).collect();
+        let (a, b) = owner.rw2(&cells[0], &cells[2]);
+        std::mem::swap(a, b);
+        let values: Vec<i32> = cells.into_iter().map(TCell::into_inner).collect();
+        assert_eq!(values, [2, 1, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "same cell")]
+    fn rw2_same_cell_panics() {
+        LCellOwner::scope(|mut owner| {
+            let cell = LCell::new(0);
+            owner.rw2(&cell, &cell);
+        });
+    }
+
+    #[test]
+    fn tcell_owner_moves_between_threads() {
+        struct Marker;
+        let mut owner = TCellOwner::<Marker>::new();
+        let cell = TCell::new(0);
+        thread::scope(|s| {
+            s.spawn(|| *owner.rw(&cell) += 1);
+        });
+        assert_eq!(cell.into_inner(), 1);
+    }
+
+    #[test]
+    fn lcell() {
+        let total = LCellOwner::scope(|mut owner| {
+            let list: Vec<Rc<LCell<u32>>> = (1..=3).map(|n| Rc::new(LCell::new(n))).collect();
+            let alias = list[0].clone();
+            *owner.rw(&alias) *= 10;
+            list.iter().map(|cell| *owner.ro(cell)).sum::<u32>()
+        });
+        assert_eq!(total, 15);
+    }
+}